@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+
+// Separator between an archive path and a member path or pattern,
+// e.g. "logs.tar.gz//**/*.log"
+pub const MEMBER_SEPARATOR: &str = "//";
+
+// Members of a tar archive selected by resolve_members, and those read ahead
+// of their turn. Reaching a member of a compressed tar means decompressing
+// everything before it, so a pass over the archive also keeps the selected
+// members it goes by, up to READ_AHEAD_BYTES, and hands them out when they
+// are asked for.
+#[derive(Debug, Default)]
+struct SelectedMembers {
+    // Modification time and size of the archive the members come from; when
+    // the archive changes, the selection and what was read ahead are dropped
+    stamp: Option<(SystemTime, u64)>,
+    names: BTreeSet<String>,
+    read: Members,
+}
+
+// Content of archive members by name
+type Members = BTreeMap<String, Vec<u8>>;
+
+static SELECTED: Mutex<BTreeMap<PathBuf, SelectedMembers>> = Mutex::new(BTreeMap::new());
+
+const READ_AHEAD_BYTES: u64 = 64 * 1024 * 1024;
+
+fn archive_stamp(archive: &Path) -> Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(archive)
+        .with_context(|| format!("Failed to read metadata: {:?}", archive))?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Tar(Option<Compression>),
+    Zip,
+}
+
+fn lowercase_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn compression_for(path: &Path) -> Option<Compression> {
+    let name = lowercase_name(path);
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Some(Compression::Gzip)
+    } else if name.ends_with(".bz2") || name.ends_with(".tbz2") {
+        Some(Compression::Bzip2)
+    } else if name.ends_with(".xz") || name.ends_with(".txz") {
+        Some(Compression::Xz)
+    } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+pub fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = lowercase_name(path);
+    if name.ends_with(".zip") {
+        return Some(ArchiveKind::Zip);
+    }
+
+    let is_tar = [".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tar.xz", ".txz", ".tar.zst", ".tzst"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix));
+    if is_tar {
+        Some(ArchiveKind::Tar(compression_for(path)))
+    } else {
+        None
+    }
+}
+
+// Split "archive.tar.gz//member/pattern" into the archive path and the member part.
+// Only separators preceded by an existing archive file count, so ordinary paths
+// containing "//" are left alone.
+pub fn split_member_path(input: &str) -> Option<(PathBuf, String)> {
+    for (index, _) in input.match_indices(MEMBER_SEPARATOR) {
+        let archive = Path::new(&input[..index]);
+        if archive_kind(archive).is_some() && archive.is_file() {
+            let member = &input[index + MEMBER_SEPARATOR.len()..];
+            return Some((archive.to_path_buf(), member.to_string()));
+        }
+    }
+    None
+}
+
+pub fn member_path(archive: &Path, member: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}{}", archive.display(), MEMBER_SEPARATOR, member))
+}
+
+// Members are stored without a leading "./" so they match user patterns directly
+fn normalize_member_name(name: &str) -> String {
+    name.trim_start_matches("./").to_string()
+}
+
+pub fn decompress_reader<'a, R: Read + 'a>(reader: R, compression: Compression) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+fn open_tar(archive: &Path, compression: Option<Compression>) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file = fs::File::open(archive)
+        .with_context(|| format!("Failed to open archive: {}", archive.display()))?;
+    let reader: Box<dyn Read> = match compression {
+        Some(compression) => decompress_reader(file, compression)?,
+        None => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+fn open_zip(archive: &Path) -> Result<zip::ZipArchive<fs::File>> {
+    let file = fs::File::open(archive)
+        .with_context(|| format!("Failed to open archive: {}", archive.display()))?;
+    zip::ZipArchive::new(file)
+        .with_context(|| format!("Failed to read zip archive: {}", archive.display()))
+}
+
+fn list_members(archive: &Path) -> Result<Vec<String>> {
+    let mut members = Vec::new();
+    match archive_kind(archive) {
+        Some(ArchiveKind::Tar(compression)) => {
+            let mut tar = open_tar(archive, compression)?;
+            for entry in tar.entries()
+                .with_context(|| format!("Failed to read tar archive: {}", archive.display()))?
+            {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    let name = entry.path()?.to_string_lossy().to_string();
+                    members.push(normalize_member_name(&name));
+                }
+            }
+        }
+        Some(ArchiveKind::Zip) => {
+            let mut zip = open_zip(archive)?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index)?;
+                if entry.is_file() {
+                    members.push(normalize_member_name(entry.name()));
+                }
+            }
+        }
+        None => return Err(anyhow::anyhow!("Not a supported archive: {}", archive.display())),
+    }
+    Ok(members)
+}
+
+// List archive members matching a glob pattern, as "archive//member" paths.
+// An empty pattern selects every file in the archive.
+pub fn resolve_members(archive: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = if pattern.is_empty() { "**" } else { pattern };
+    let matcher = glob::Pattern::new(pattern)
+        .with_context(|| format!("Invalid glob pattern: {}", pattern))?;
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    let members: Vec<String> = list_members(archive)?
+        .into_iter()
+        .filter(|member| matcher.matches_with(member, options))
+        .collect();
+    if let Some(ArchiveKind::Tar(_)) = archive_kind(archive) {
+        let stamp = archive_stamp(archive)?;
+        let mut selected = SELECTED.lock().unwrap();
+        let selected = selected.entry(archive.to_path_buf()).or_default();
+        if selected.stamp != Some(stamp) {
+            *selected = SelectedMembers { stamp: Some(stamp), ..Default::default() };
+        }
+        selected.names.extend(members.iter().cloned());
+        // Resolving again, as --watch does, starts a new round of reads
+        selected.read.clear();
+    }
+    Ok(members.iter().map(|member| member_path(archive, member)).collect())
+}
+
+// Read member from a tar archive in one pass, along with the other selected
+// members met on the way while they fit in budget bytes
+fn read_tar_member(
+    archive: &Path,
+    compression: Option<Compression>,
+    member: &str,
+    selected: &BTreeSet<String>,
+    mut budget: u64,
+) -> Result<(Option<Vec<u8>>, Members)> {
+    let mut wanted = None;
+    let mut read_ahead = BTreeMap::new();
+    let mut tar = open_tar(archive, compression)?;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = normalize_member_name(&entry.path()?.to_string_lossy());
+        let size = entry.header().size()?;
+        if name == member && wanted.is_none() {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            wanted = Some(bytes);
+        } else if name != member && selected.contains(&name) && size <= budget && !read_ahead.contains_key(&name) {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            budget -= size;
+            read_ahead.insert(name, bytes);
+        }
+        if wanted.is_some() && selected.iter().all(|name| name == member || read_ahead.contains_key(name)) {
+            break;
+        }
+    }
+    Ok((wanted, read_ahead))
+}
+
+fn read_zip_member(archive: &Path, member: &str) -> Result<Option<Vec<u8>>> {
+    let mut zip = open_zip(archive)?;
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        if normalize_member_name(entry.name()) == member {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            return Ok(Some(bytes));
+        }
+    }
+    Ok(None)
+}
+
+fn read_member(archive: &Path, member: &str) -> Result<Vec<u8>> {
+    let not_found = || anyhow::anyhow!("Archive member not found: {}//{}", archive.display(), member);
+    // Zip members are reached directly through the central directory
+    let compression = match archive_kind(archive) {
+        Some(ArchiveKind::Tar(compression)) => compression,
+        Some(ArchiveKind::Zip) => return read_zip_member(archive, member)?.ok_or_else(not_found),
+        None => return Err(anyhow::anyhow!("Not a supported archive: {}", archive.display())),
+    };
+
+    let stamp = archive_stamp(archive)?;
+    let mut selected = SELECTED.lock().unwrap();
+    // Sorted inputs read the members of one archive together, so what other
+    // archives read ahead is dropped to keep to a single budget
+    for (_, other) in selected.iter_mut().filter(|(path, _)| path.as_path() != archive) {
+        other.read.clear();
+    }
+    let selected = selected.entry(archive.to_path_buf()).or_default();
+    if selected.stamp != Some(stamp) {
+        selected.read.clear();
+        selected.stamp = Some(stamp);
+    }
+    if let Some(bytes) = selected.read.remove(member) {
+        return Ok(bytes);
+    }
+    // Not read yet, or read before (for instance to count tokens) and handed out
+    let (wanted, read_ahead) = read_tar_member(archive, compression, member, &selected.names, READ_AHEAD_BYTES)?;
+    selected.read = read_ahead;
+    wanted.ok_or_else(not_found)
+}
+
+// Filesystem metadata of an input; archive members report their archive's metadata
+pub fn input_metadata(path: &Path) -> Result<fs::Metadata> {
+    let metadata_path = match split_member_path(&path.to_string_lossy()) {
+        Some((archive, _)) if !path.is_file() => archive,
+        _ => path.to_path_buf(),
+    };
+    fs::metadata(&metadata_path)
+        .with_context(|| format!("Failed to read metadata: {:?}", metadata_path))
+}
+
+// Modification time of an input in seconds since the Unix epoch
+pub fn input_mtime(path: &Path) -> Result<u64> {
+    let modified = input_metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0))
+}
+
+// Read the raw bytes of an input, which may be a plain file, an archive member,
+// or (when decompress is on) a single-file compressed stream
+pub fn read_input(path: &Path, decompress: bool) -> Result<Vec<u8>> {
+    if !path.is_file()
+        && let Some((archive, member)) = split_member_path(&path.to_string_lossy())
+    {
+        return read_member(&archive, &member);
+    }
+
+    if decompress && let Some(compression) = compression_for(path) {
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to read file: {:?}", path))?;
+        let mut bytes = Vec::new();
+        decompress_reader(file, compression)?
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to decompress file: {:?}", path))?;
+        return Ok(bytes);
+    }
+
+    fs::read(path).with_context(|| format!("Failed to read file: {:?}", path))
+}
+
+// Open an input to be read as a stream, like read_input; archive members are
+// still read in whole
+pub fn open_input(path: &Path, decompress: bool) -> Result<Box<dyn Read>> {
+    if !path.is_file()
+        && let Some((archive, member)) = split_member_path(&path.to_string_lossy())
+    {
+        return Ok(Box::new(std::io::Cursor::new(read_member(&archive, &member)?)));
+    }
+
+    let file = fs::File::open(path).with_context(|| format!("Failed to read file: {:?}", path))?;
+    match compression_for(path) {
+        Some(compression) if decompress => decompress_reader(file, compression),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn write_tar_gz(path: &Path, entries: &[(&str, &str)]) -> Result<()> {
+        let file = fs::File::create(path)?;
+        let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_tar_members_with_glob() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let archive = temp_dir.path().join("logs.tar.gz");
+        write_tar_gz(&archive, &[
+            ("app.log", "top"),
+            ("nested/db.log", "nested"),
+            ("nested/readme.txt", "ignored"),
+        ])?;
+
+        let all_logs = resolve_members(&archive, "**/*.log")?;
+        assert_eq!(all_logs.len(), 2);
+
+        let top_logs = resolve_members(&archive, "*.log")?;
+        assert_eq!(top_logs, vec![member_path(&archive, "app.log")]);
+
+        let content = read_input(&member_path(&archive, "nested/db.log"), false)?;
+        assert_eq!(content, b"nested");
+        // The other selected member was read in the same pass
+        assert_eq!(SELECTED.lock().unwrap()[&archive].read.keys().collect::<Vec<_>>(), vec!["app.log"]);
+        assert_eq!(read_input(&all_logs[0], false)?, b"top");
+        assert!(SELECTED.lock().unwrap()[&archive].read.is_empty());
+
+        // Members only go ahead of their turn within the budget
+        let selected = BTreeSet::from(["app.log".to_string(), "nested/db.log".to_string()]);
+        let (wanted, read_ahead) = read_tar_member(&archive, Some(Compression::Gzip), "nested/db.log", &selected, 2)?;
+        assert_eq!(wanted.as_deref(), Some(&b"nested"[..]));
+        assert!(read_ahead.is_empty());
+
+        // A rewritten archive drops the selection made from the old one
+        write_tar_gz(&archive, &[("other.log", "new content")])?;
+        let file = fs::File::options().write(true).open(&archive)?;
+        file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs(1))?;
+        resolve_members(&archive, "")?;
+        let selected = SELECTED.lock().unwrap();
+        assert_eq!(selected[&archive].names.iter().collect::<Vec<_>>(), vec!["other.log"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_zip_member() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let archive = temp_dir.path().join("bundle.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive)?);
+        zip.start_file("data/a.txt", zip::write::SimpleFileOptions::default())?;
+        zip.write_all(b"from zip")?;
+        zip.finish()?;
+
+        let input = format!("{}//data/*.txt", archive.display());
+        let (archive_path, pattern) = split_member_path(&input).unwrap();
+        let members = resolve_members(&archive_path, &pattern)?;
+        assert_eq!(members.len(), 1);
+        assert_eq!(read_input(&members[0], false)?, b"from zip");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_single_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("app.log.1.gz");
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(&path)?, flate2::Compression::default());
+        encoder.write_all(b"rotated line")?;
+        encoder.finish()?;
+
+        assert_eq!(read_input(&path, true)?, b"rotated line");
+        assert_ne!(read_input(&path, false)?, b"rotated line");
+        Ok(())
+    }
+}
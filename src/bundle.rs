@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Context, Result};
+
+use crate::{archive, includes, relative_path};
+
+// Every file in a bundle starts with a header carrying its path and exact byte
+// length, e.g. "==> src/main.rs (1234 bytes) <==". The content follows verbatim
+// and is terminated by a single newline. Because the length is known up front,
+// content that itself looks like a header is never mistaken for one. Binary
+// inputs are written as the bytes read, marked "(1234 raw bytes)".
+const HEADER_PREFIX: &str = "==> ";
+const HEADER_SUFFIX: &str = " bytes) <==";
+const LENGTH_SEPARATOR: &str = " (";
+const RAW_MARKER: &str = " raw";
+
+// A file of a bundle; raw content is the input as read, other content is text
+// decoded to UTF-8
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub path: String,
+    pub content: Vec<u8>,
+    pub raw: bool,
+}
+
+pub fn header_line(relative_path: &str, length: usize, raw: bool) -> String {
+    let marker = if raw { RAW_MARKER } else { "" };
+    format!("{}{}{}{}{}{}\n", HEADER_PREFIX, relative_path, LENGTH_SEPARATOR, length, marker, HEADER_SUFFIX)
+}
+
+// Returns the path, content length and raw marker announced by a header line
+// (without its newline)
+pub fn parse_header(line: &str) -> Option<(String, usize, bool)> {
+    let inner = line.strip_prefix(HEADER_PREFIX)?.strip_suffix(HEADER_SUFFIX)?;
+    let (path, length) = inner.rsplit_once(LENGTH_SEPARATOR)?;
+    if path.is_empty() {
+        return None;
+    }
+    let (length, raw) = match length.strip_suffix(RAW_MARKER) {
+        Some(length) => (length, true),
+        None => (length, false),
+    };
+    Some((path.to_string(), length.parse().ok()?, raw))
+}
+
+// Paths written in the headers of a bundle: relative to the current directory,
+// or when an input lies outside it, to the deepest directory holding every
+// input, so that split does not recreate the directories above them. A path
+// with a newline would end its header early, so it is refused.
+pub fn entry_names(files: &[PathBuf]) -> Result<Vec<String>> {
+    if let Some(file) = files.iter().find(|file| file.as_os_str().as_encoded_bytes().contains(&b'\n')) {
+        return Err(anyhow::anyhow!("Cannot write a header for a path containing a newline: {:?}", file));
+    }
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    let absolute: Vec<PathBuf> = files.iter().map(|file| includes::normalize(&current_dir.join(file))).collect();
+    if absolute.iter().all(|file| file.starts_with(&current_dir)) {
+        return Ok(files.iter().map(|file| relative_path(file)).collect());
+    }
+    let mut root = absolute[0].parent().map(Path::to_path_buf).unwrap_or_default();
+    for file in &absolute {
+        while !file.starts_with(&root) && root.pop() {}
+    }
+    Ok(absolute.iter().map(|file| relative_path(file.strip_prefix(&root).unwrap_or(file))).collect())
+}
+
+// Only plain relative paths may be restored, so a crafted bundle cannot write
+// outside the destination directory
+fn safe_destination(out_dir: &Path, relative_path: &str) -> Result<PathBuf> {
+    let path = Path::new(relative_path);
+    if path.components().all(|component| matches!(component, Component::Normal(_))) {
+        Ok(out_dir.join(path))
+    } else {
+        Err(anyhow::anyhow!("Refusing to restore unsafe path: {}", relative_path))
+    }
+}
+
+// Parse a bundle into its records
+pub fn parse_bundle(bytes: &[u8]) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let line_end = bytes[position..].iter()
+            .position(|&byte| byte == b'\n')
+            .map(|offset| position + offset)
+            .ok_or_else(|| anyhow::anyhow!("Truncated header at byte {}", position))?;
+        let line = String::from_utf8_lossy(&bytes[position..line_end]);
+        let (path, length, raw) = parse_header(&line)
+            .ok_or_else(|| anyhow::anyhow!("Expected a file header at byte {}, found: {}", position, line))?;
+
+        let content_start = line_end + 1;
+        let content_end = content_start + length;
+        if content_end >= bytes.len() || bytes[content_end] != b'\n' {
+            return Err(anyhow::anyhow!("Content of {} is truncated or does not match its declared length of {} bytes", path, length));
+        }
+
+        records.push(Record { path, content: bytes[content_start..content_end].to_vec(), raw });
+        position = content_end + 1;
+    }
+
+    Ok(records)
+}
+
+// Recreate the files of a bundle under out_dir, returning the written paths
+pub fn split_bundle(bundle_path: &Path, out_dir: &Path) -> Result<Vec<PathBuf>> {
+    // Compressed bundles (.gz, .zst, ...) are unpacked on the fly
+    let bytes = archive::read_input(bundle_path, true)?;
+    let records = parse_bundle(&bytes)
+        .with_context(|| format!("Invalid bundle: {}", bundle_path.display()))?;
+
+    let mut written = Vec::new();
+    for Record { path, content, .. } in records {
+        let destination = safe_destination(out_dir, &path)?;
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        fs::write(&destination, content)
+            .with_context(|| format!("Failed to write file: {}", destination.display()))?;
+        written.push(destination);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_header() {
+        let line = header_line("dir/odd (name).txt", 42, false);
+        assert_eq!(line, "==> dir/odd (name).txt (42 bytes) <==\n");
+        assert_eq!(parse_header(line.trim_end()), Some(("dir/odd (name).txt".to_string(), 42, false)));
+        assert_eq!(header_line("logo.png", 7, true), "==> logo.png (7 raw bytes) <==\n");
+        assert_eq!(parse_header("==> logo.png (7 raw bytes) <=="), Some(("logo.png".to_string(), 7, true)));
+        assert_eq!(parse_header("==> missing length <=="), None);
+        assert_eq!(parse_header("plain text"), None);
+    }
+
+    #[test]
+    fn test_content_that_looks_like_a_header() -> Result<()> {
+        let tricky = "==> fake.txt (3 bytes) <==\nabc\n";
+        let mut bundle = header_line("real.txt", tricky.len(), false);
+        bundle.push_str(tricky);
+        bundle.push('\n');
+        bundle.push_str(&header_line("empty.txt", 0, false));
+        bundle.push('\n');
+
+        let records = parse_bundle(bundle.as_bytes())?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], Record { path: "real.txt".to_string(), content: tricky.as_bytes().to_vec(), raw: false });
+        assert_eq!(records[1], Record { path: "empty.txt".to_string(), content: Vec::new(), raw: false });
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_names_outside_the_current_directory() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![temp_dir.path().join("a.txt"), temp_dir.path().join("sub/b.txt")];
+        assert_eq!(entry_names(&files)?, vec!["a.txt", "sub/b.txt"]);
+        assert_eq!(entry_names(&files[1..])?, vec!["b.txt"]);
+        assert_eq!(entry_names(&[PathBuf::from("src/main.rs")])?, vec!["src/main.rs"]);
+        assert!(entry_names(&[PathBuf::from("odd\nname.txt")]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_rejects_unsafe_paths() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let bundle_path = temp_dir.path().join("bundle.txt");
+        fs::write(&bundle_path, format!("{}x\n", header_line("../escape.txt", 1, false)))?;
+
+        assert!(split_bundle(&bundle_path, &temp_dir.path().join("out")).is_err());
+        assert!(!temp_dir.path().join("escape.txt").exists());
+        Ok(())
+    }
+}
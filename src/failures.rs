@@ -0,0 +1,94 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::{archive, relative_path};
+
+// Exit code of a --keep-going run that skipped inputs, leaving a partial output
+pub const EXIT_PARTIAL: i32 = 2;
+
+// Files and directories skipped by --keep-going, with why
+#[derive(Debug, Clone, Default)]
+pub struct Failures(Arc<Mutex<Vec<(String, String)>>>);
+
+impl Failures {
+    // An input read again after failing is only listed once
+    pub fn record(&self, path: &Path, error: &anyhow::Error) {
+        let path = relative_path(path);
+        let mut failures = self.0.lock().unwrap();
+        if !failures.iter().any(|(failed, _)| *failed == path) {
+            failures.push((path, format!("{:#}", error)));
+        }
+    }
+
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        let path = relative_path(path);
+        self.0.lock().unwrap().iter().any(|(failed, _)| *failed == path)
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+// With --keep-going, record a failure and carry on with nothing from path
+pub fn skip_on_error<T: Default>(result: Result<T>, path: &Path, failures: Option<&Failures>) -> Result<T> {
+    match (result, failures) {
+        (Err(error), Some(failures)) => {
+            failures.record(path, &error);
+            Ok(T::default())
+        }
+        (result, _) => result,
+    }
+}
+
+// Catch inputs that vanished or cannot be opened since they were resolved,
+// before anything is written
+pub fn check_readable(file_path: &Path) -> Result<()> {
+    let metadata = archive::input_metadata(file_path)?;
+    if metadata.is_file() && file_path.is_file() {
+        std::fs::File::open(file_path)?;
+    }
+    Ok(())
+}
+
+pub fn print_failure_summary(failures: &[(String, String)]) {
+    eprintln!("Skipped {} unreadable inputs:", failures.len());
+    for (path, reason) in failures {
+        eprintln!("  {}: {}", path, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_skip_on_error_records_failures() {
+        let failures = Failures::default();
+        let skipped: Vec<u8> = skip_on_error(Err(anyhow::anyhow!("Permission denied")), Path::new("secret.txt"), Some(&failures)).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(failures.list(), vec![("secret.txt".to_string(), "Permission denied".to_string())]);
+
+        assert!(skip_on_error::<Vec<u8>>(Err(anyhow::anyhow!("fatal")), Path::new("a.txt"), None).is_err());
+        assert_eq!(skip_on_error(Ok(vec![1u8]), Path::new("a.txt"), Some(&failures)).unwrap(), vec![1]);
+        assert_eq!(failures.list().len(), 1);
+    }
+
+    #[test]
+    fn test_check_readable() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file_path = temp_dir.path().join("a.txt");
+        std::fs::write(&file_path, "a\n")?;
+        assert!(check_readable(&file_path).is_ok());
+        std::fs::remove_file(&file_path)?;
+        assert!(check_readable(&file_path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{archive, manifest};
+
+// Exit code of an --incremental run that left the output untouched, with
+// --exit-code
+pub const EXIT_UP_TO_DATE: i32 = 3;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputFingerprint {
+    pub path: String,
+    pub size: u64,
+    // Nanoseconds since the Unix epoch; not compared when hashing
+    pub mtime_ns: u64,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputFingerprint {
+    pub path: String,
+    pub size: u64,
+    // Only recorded with --incremental-hash, so an output edited in place
+    // without changing its size is rebuilt too
+    pub sha256: Option<String>,
+}
+
+// What an output was built from, stored beside it to skip identical rebuilds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub version: String,
+    // Command-line arguments of the run, so changing an option forces a rebuild
+    pub arguments: Vec<String>,
+    pub inputs: Vec<InputFingerprint>,
+    pub outputs: Vec<OutputFingerprint>,
+}
+
+// "bundle.txt" is fingerprinted in "bundle.txt.fingerprint"
+pub fn fingerprint_path(output_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.fingerprint", output_path))
+}
+
+fn input_fingerprint(file_path: &Path, hash: bool, decompress: bool) -> Result<InputFingerprint> {
+    let metadata = archive::input_metadata(file_path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(InputFingerprint {
+        path: file_path.display().to_string(),
+        size: metadata.len(),
+        mtime_ns: modified.as_nanos() as u64,
+        sha256: if hash { Some(manifest::sha256_hex(&archive::read_input(file_path, decompress)?)) } else { None },
+    })
+}
+
+pub fn inputs_fingerprint(files: &[PathBuf], hash: bool, decompress: bool) -> Result<Vec<InputFingerprint>> {
+    files.iter().map(|file_path| input_fingerprint(file_path, hash, decompress)).collect()
+}
+
+pub fn outputs_fingerprint(outputs: &[PathBuf], hash: bool) -> Result<Vec<OutputFingerprint>> {
+    outputs.iter()
+        .map(|output_path| {
+            let metadata = fs::metadata(output_path).with_context(|| format!("Failed to read metadata: {:?}", output_path))?;
+            Ok(OutputFingerprint {
+                path: output_path.display().to_string(),
+                size: metadata.len(),
+                sha256: if hash { Some(manifest::output_record(output_path)?.sha256) } else { None },
+            })
+        })
+        .collect()
+}
+
+impl Fingerprint {
+    // The previous build is current when it was made by this version with the
+    // same arguments from the same inputs, and its outputs are still in place
+    pub fn is_current(&self, arguments: &[String], inputs: &[InputFingerprint]) -> bool {
+        let same_inputs = self.inputs.len() == inputs.len()
+            && self.inputs.iter().zip(inputs).all(|(previous, current)| match (&previous.sha256, &current.sha256) {
+                (Some(previous_hash), Some(current_hash)) => {
+                    previous.path == current.path && previous.size == current.size && previous_hash == current_hash
+                }
+                _ => previous == current,
+            });
+        let outputs_in_place = self.outputs.iter().all(|output| {
+            fs::metadata(&output.path).is_ok_and(|metadata| metadata.len() == output.size)
+                && output.sha256.as_ref().is_none_or(|sha256| {
+                    manifest::output_record(Path::new(&output.path)).is_ok_and(|current| current.sha256 == *sha256)
+                })
+        });
+        self.version == env!("CARGO_PKG_VERSION") && self.arguments == arguments && same_inputs && outputs_in_place
+    }
+}
+
+// A missing or unreadable fingerprint just means the output is rebuilt
+pub fn read_fingerprint(fingerprint_path: &Path) -> Option<Fingerprint> {
+    serde_json::from_str(&fs::read_to_string(fingerprint_path).ok()?).ok()
+}
+
+pub fn write_fingerprint(fingerprint_path: &Path, fingerprint: &Fingerprint) -> Result<()> {
+    fs::write(fingerprint_path, serde_json::to_string_pretty(fingerprint)? + "\n")
+        .with_context(|| format!("Failed to write fingerprint: {}", fingerprint_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fingerprint(arguments: &[String], files: &[PathBuf], outputs: &[PathBuf], hash: bool) -> Result<Fingerprint> {
+        Ok(Fingerprint {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            arguments: arguments.to_vec(),
+            inputs: inputs_fingerprint(files, hash, false)?,
+            outputs: outputs_fingerprint(outputs, hash)?,
+        })
+    }
+
+    #[test]
+    fn test_fingerprint_detects_changes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("a.txt");
+        let output = temp_dir.path().join("out.txt");
+        fs::write(&input, "hello\n")?;
+        fs::write(&output, "hello\n")?;
+        let arguments = vec!["-o".to_string(), output.display().to_string()];
+        let files = vec![input.clone()];
+
+        let previous = fingerprint(&arguments, &files, std::slice::from_ref(&output), false)?;
+        assert!(previous.is_current(&arguments, &inputs_fingerprint(&files, false, false)?));
+        assert!(!previous.is_current(&["-r".to_string()], &inputs_fingerprint(&files, false, false)?));
+
+        fs::write(&input, "hello, world\n")?;
+        assert!(!previous.is_current(&arguments, &inputs_fingerprint(&files, false, false)?));
+        fs::write(&output, "")?;
+        let previous = fingerprint(&arguments, &files, std::slice::from_ref(&output), false)?;
+        fs::remove_file(&output)?;
+        assert!(!previous.is_current(&arguments, &inputs_fingerprint(&files, false, false)?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashed_fingerprint_ignores_mtime() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("a.txt");
+        fs::write(&input, "hello\n")?;
+        let files = vec![input.clone()];
+
+        let previous = fingerprint(&[], &files, &[], true)?;
+        let mut current = inputs_fingerprint(&files, true, false)?;
+        current[0].mtime_ns += 1_000_000_000;
+        assert!(previous.is_current(&[], &current));
+        current[0].sha256 = Some(manifest::sha256_hex(b"other\n"));
+        assert!(!previous.is_current(&[], &current));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashed_fingerprint_detects_output_edits() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("a.txt");
+        let output = temp_dir.path().join("out.txt");
+        fs::write(&input, "hello\n")?;
+        fs::write(&output, "hello\n")?;
+        let files = vec![input.clone()];
+
+        let previous = fingerprint(&[], &files, std::slice::from_ref(&output), true)?;
+        assert!(previous.is_current(&[], &inputs_fingerprint(&files, true, false)?));
+        // Same size, different content
+        fs::write(&output, "HELLO\n")?;
+        assert!(!previous.is_current(&[], &inputs_fingerprint(&files, true, false)?));
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use encoding_rs::Decoder;
+
+use crate::{detect_encoding, lines, output, relative_path, ConcatOptions, DETECTION_BYTES};
+
+// How often the inputs are checked for new bytes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Identity of the file behind a path, which changes when a log is rotated
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+// Apply the options that work line by line to newly added lines; --follow
+// rejects the ones that need a whole file
+fn transform_lines(file_path: &Path, text: String, options: &ConcatOptions) -> String {
+    let origins = lines::source_origins(&text);
+    let (text, origins) = match &options.redactor {
+        Some(redactor) => redactor.redact(file_path, &text, &origins),
+        None => (text, origins),
+    };
+    match &options.line_filter {
+        Some(filter) => filter.apply(&relative_path(file_path), &text, &origins).map(|(text, _)| text).unwrap_or_default(),
+        None => text,
+    }
+}
+
+// An input being followed, through the file opened at its path, which may
+// since have been rotated away
+struct FollowedFile {
+    path: PathBuf,
+    file: Option<File>,
+    id: u64,
+    position: u64,
+    decoder: Decoder,
+    // The file had bytes to detect its encoding from when it was opened;
+    // otherwise it is detected from the first bytes written to it
+    detected: bool,
+    // Text read but not yet written, held back until its line is complete
+    pending: String,
+}
+
+impl FollowedFile {
+    // Open the file now at the path, skipping its content when at_end is set.
+    // A missing file is picked up once it is created.
+    fn open(&mut self, at_end: bool) -> Result<()> {
+        self.file = None;
+        let Ok(mut file) = File::open(&self.path) else {
+            return Ok(());
+        };
+        let metadata = file.metadata()?;
+        let mut head = Vec::new();
+        (&mut file).take(DETECTION_BYTES as u64).read_to_end(&mut head)
+            .with_context(|| format!("Failed to read: {}", self.path.display()))?;
+        self.decoder = detect_encoding(&head).new_decoder_with_bom_removal();
+        self.detected = !head.is_empty();
+        self.id = file_id(&metadata);
+        self.position = if at_end { metadata.len() } else { 0 };
+        file.seek(SeekFrom::Start(self.position))?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn read_available(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            let mut bytes = Vec::new();
+            let read = file.read_to_end(&mut bytes)
+                .with_context(|| format!("Failed to read: {}", self.path.display()))?;
+            self.position += read as u64;
+            if !self.detected && !bytes.is_empty() {
+                self.decoder = detect_encoding(&bytes).new_decoder_with_bom_removal();
+                self.detected = true;
+            }
+            // The decoder keeps a character cut at the end for the next read
+            self.pending.reserve(self.decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3));
+            let _ = self.decoder.decode_to_string(&bytes, &mut self.pending, false);
+        }
+        Ok(())
+    }
+
+    // Complete lines added since the last call
+    fn new_lines(&mut self) -> Result<String> {
+        // Drain the open file first: after a rotation it holds the last lines
+        // written before the switch
+        self.read_available()?;
+        match fs::metadata(&self.path) {
+            Ok(metadata) if self.file.is_none() || file_id(&metadata) != self.id => {
+                // Rotated or newly created: follow the new file from its start
+                self.open(false)?;
+                self.read_available()?;
+            }
+            Ok(metadata) if metadata.len() < self.position => {
+                // Truncated in place, as logrotate's copytruncate does
+                self.open(false)?;
+                self.read_available()?;
+            }
+            _ => {}
+        }
+        Ok(match self.pending.rfind('\n') {
+            Some(end) => self.pending.drain(..=end).collect(),
+            None => String::new(),
+        })
+    }
+}
+
+// Appends what is added to the inputs to the output, like `tail -F` does
+pub struct Follower {
+    files: Vec<FollowedFile>,
+    // Input of the last lines written, to mark where lines of another start
+    last_written: Option<usize>,
+    // The output ends inside a line
+    needs_newline: bool,
+}
+
+impl Follower {
+    // Follow each input from its current end
+    pub fn new(files: &[PathBuf], needs_newline: bool) -> Result<Self> {
+        let mut followed = Vec::with_capacity(files.len());
+        for path in files {
+            let mut file = FollowedFile {
+                path: path.clone(),
+                file: None,
+                id: 0,
+                position: 0,
+                decoder: encoding_rs::UTF_8.new_decoder(),
+                detected: false,
+                pending: String::new(),
+            };
+            file.open(true)?;
+            followed.push(file);
+        }
+        Ok(Follower { files: followed, last_written: None, needs_newline })
+    }
+
+    // Write the lines completed since the last poll, decoded and transformed;
+    // with several inputs each run of lines from one input starts with a
+    // "==> path <==" marker
+    pub fn poll<W: Write>(&mut self, output: &mut W, options: &ConcatOptions) -> Result<bool> {
+        let markers = self.files.len() > 1;
+        let mut written = false;
+        for (index, file) in self.files.iter_mut().enumerate() {
+            let lines = file.new_lines()?;
+            let lines = transform_lines(&file.path, lines, options);
+            if lines.is_empty() {
+                continue;
+            }
+            if self.needs_newline {
+                output.write_all(b"\n")?;
+                self.needs_newline = false;
+            }
+            if markers && self.last_written != Some(index) {
+                writeln!(output, "==> {} <==", relative_path(&file.path))?;
+            }
+            output.write_all(lines.as_bytes())?;
+            self.last_written = Some(index);
+            written = true;
+        }
+        if written {
+            output.flush()?;
+        }
+        Ok(written)
+    }
+}
+
+// Keep appending new lines of the inputs to the output until interrupted
+pub fn follow(files: &[PathBuf], output_path: &str, options: &ConcatOptions) -> Result<()> {
+    let needs_newline = output::ends_without_newline(Path::new(output_path))?;
+    let mut output = fs::OpenOptions::new().append(true).open(output_path)
+        .with_context(|| format!("Failed to open output file: {}", output_path))?;
+    let mut follower = Follower::new(files, needs_newline)?;
+    status!("Following {} files (Ctrl+C to stop)", files.len());
+    loop {
+        follower.poll(&mut output, options)?;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines;
+    use std::fs::OpenOptions;
+    use tempfile::TempDir;
+
+    fn append(path: &Path, text: &str) -> Result<()> {
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_appends_complete_lines() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let a = temp_dir.path().join("a.log");
+        let b = temp_dir.path().join("b.log");
+        fs::write(&a, "old\n")?;
+        fs::write(&b, "")?;
+
+        let mut follower = Follower::new(&[a.clone(), b.clone()], true)?;
+        let mut output = Vec::new();
+        assert!(!follower.poll(&mut output, &ConcatOptions::default())?);
+
+        append(&a, "a1\na2")?;
+        append(&b, "b1\n")?;
+        follower.poll(&mut output, &ConcatOptions::default())?;
+        append(&a, "\n")?;
+        follower.poll(&mut output, &ConcatOptions::default())?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!("\n==> {} <==\na1\n==> {} <==\nb1\n==> {} <==\na2\n", relative_path(&a), relative_path(&b), relative_path(&a))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_decodes_and_filters_lines() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let log = temp_dir.path().join("app.log");
+        let utf16 = |text: &str| text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect::<Vec<u8>>();
+        fs::write(&log, [vec![0xFF, 0xFE], utf16("start\n")].concat())?;
+        let options = ConcatOptions {
+            line_filter: Some(lines::LineFilter { grep: Some(regex::Regex::new("ERROR")?), ..Default::default() }),
+            ..Default::default()
+        };
+        let mut follower = Follower::new(std::slice::from_ref(&log), false)?;
+        let mut output = Vec::new();
+
+        // A character cut between two reads is decoded once complete
+        let appended = utf16("INFO ok\nERROR caf\u{e9}\n");
+        let (first, second) = appended.split_at(appended.len() - 3);
+        OpenOptions::new().append(true).open(&log)?.write_all(first)?;
+        follower.poll(&mut output, &options)?;
+        OpenOptions::new().append(true).open(&log)?.write_all(second)?;
+        follower.poll(&mut output, &options)?;
+        assert_eq!(String::from_utf8(output)?, "ERROR caf\u{e9}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_detects_encoding_of_empty_file_once_written() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let log = temp_dir.path().join("app.log");
+        fs::write(&log, "")?;
+        let mut follower = Follower::new(std::slice::from_ref(&log), false)?;
+        let mut output = Vec::new();
+
+        OpenOptions::new().append(true).open(&log)?.write_all(b"caf\xe9\n")?;
+        follower.poll(&mut output, &ConcatOptions::default())?;
+        assert_eq!(String::from_utf8(output)?, "caf\u{e9}\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_rotation_and_truncation() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let log = temp_dir.path().join("app.log");
+        fs::write(&log, "start\n")?;
+        let mut follower = Follower::new(std::slice::from_ref(&log), false)?;
+        let mut output = Vec::new();
+
+        // Lines written just before the rotation are still picked up
+        append(&log, "before rotation\n")?;
+        fs::rename(&log, temp_dir.path().join("app.log.1"))?;
+        fs::write(&log, "after rotation\n")?;
+        follower.poll(&mut output, &ConcatOptions::default())?;
+
+        fs::write(&log, "truncated\n")?;
+        follower.poll(&mut output, &ConcatOptions::default())?;
+        assert_eq!(String::from_utf8(output)?, "before rotation\nafter rotation\ntruncated\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use regex::Regex;
+
+use crate::lines::Origins;
+use crate::{archive, decode_with_encoding_detection, relative_path};
+
+// Inline the files named by include directives such as "-- include: other.sql"
+// or "@import 'base.css'"
+#[derive(Debug, Clone)]
+pub struct IncludeExpander {
+    // Matches a directive line; the first capture group is the included path
+    pub pattern: Regex,
+    // Directives nested deeper than this are left as they are
+    pub max_depth: usize,
+    pub decompress: bool,
+    // Files inlined into each input, in order of first inclusion
+    dependencies: Arc<Mutex<BTreeMap<PathBuf, Vec<PathBuf>>>>,
+    // Directives left unexpanded in each input, kept so that an input read
+    // more than once is only warned about once
+    warnings: Arc<Mutex<BTreeMap<PathBuf, Vec<String>>>>,
+}
+
+// Resolve "." and ".." lexically so the same file is recognized in a cycle
+// whichever way it is reached
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+impl IncludeExpander {
+    pub fn new(pattern: Regex, max_depth: usize, decompress: bool) -> Self {
+        IncludeExpander { pattern, max_depth, decompress, dependencies: Default::default(), warnings: Default::default() }
+    }
+
+    // Included lines have no origin in file_path
+    pub fn expand(&self, file_path: &Path, content: &str, origins: &[Option<usize>]) -> (String, Origins) {
+        let mut dependencies = Vec::new();
+        let mut warnings = Vec::new();
+        let mut expanded_origins = Origins::new();
+        let expanded = self.expand_nested(content, &mut vec![normalize(file_path)], &mut dependencies, &mut warnings, &mut |line| {
+            expanded_origins.push(line.and_then(|line| origins.get(line).copied().flatten()));
+        });
+        self.dependencies.lock().unwrap().insert(file_path.to_path_buf(), dependencies);
+        self.warnings.lock().unwrap().insert(file_path.to_path_buf(), warnings);
+        (expanded, expanded_origins)
+    }
+
+    // stack holds the including files, outermost first. Each output line is
+    // passed to output_line as the zero-based line of content it was copied
+    // from, or None when it was included.
+    fn expand_nested(
+        &self,
+        content: &str,
+        stack: &mut Vec<PathBuf>,
+        dependencies: &mut Vec<PathBuf>,
+        warnings: &mut Vec<String>,
+        output_line: &mut dyn FnMut(Option<usize>),
+    ) -> String {
+        let including = stack.last().unwrap().clone();
+        let mut expanded = String::with_capacity(content.len());
+        for (index, line) in content.split_inclusive('\n').enumerate() {
+            let Some(target) = self.pattern.captures(line.trim_end_matches(['\r', '\n'])).and_then(|captures| captures.get(1)) else {
+                expanded.push_str(line);
+                output_line(Some(index));
+                continue;
+            };
+            let location = format!("{}:{}", relative_path(&including), index + 1);
+            let included = normalize(&including.parent().unwrap_or(Path::new("")).join(target.as_str().trim()));
+
+            if stack.contains(&included) {
+                warnings.push(format!("Include cycle at {}: {} is already being included", location, relative_path(&included)));
+                expanded.push_str(line);
+                output_line(Some(index));
+                continue;
+            }
+            if stack.len() > self.max_depth {
+                warnings.push(format!("Include depth limit ({}) reached at {}", self.max_depth, location));
+                expanded.push_str(line);
+                output_line(Some(index));
+                continue;
+            }
+            let bytes = match archive::read_input(&included, self.decompress) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    warnings.push(format!("Cannot include {} at {}: {:#}", relative_path(&included), location, error));
+                    expanded.push_str(line);
+                    output_line(Some(index));
+                    continue;
+                }
+            };
+
+            if !dependencies.contains(&included) {
+                dependencies.push(included.clone());
+            }
+            stack.push(included.clone());
+            let nested = self.expand_nested(&decode_with_encoding_detection(&bytes).0, stack, dependencies, warnings, &mut |_| {});
+            stack.pop();
+
+            let block = format!("==> {} (included from {}) <==\n{}\n", relative_path(&included), location, nested.trim_end());
+            block.matches('\n').for_each(|_| output_line(None));
+            expanded.push_str(&block);
+        }
+        expanded
+    }
+
+    pub fn dependencies(&self) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+        self.dependencies.lock().unwrap().clone()
+    }
+
+    pub fn warnings(&self) -> BTreeMap<PathBuf, Vec<String>> {
+        self.warnings.lock().unwrap().clone()
+    }
+}
+
+// Each warning once, even when several inputs include the same broken file
+pub fn print_warnings(warnings: &BTreeMap<PathBuf, Vec<String>>) {
+    let mut printed = Vec::new();
+    for warning in warnings.values().flatten() {
+        if !printed.contains(&warning) {
+            eprintln!("Warning: {}", warning);
+            printed.push(warning);
+        }
+    }
+}
+
+// One "input: included files" line per input that included something
+pub fn print_dependencies(dependencies: &BTreeMap<PathBuf, Vec<PathBuf>>) {
+    let including: Vec<_> = dependencies.iter().filter(|(_, included)| !included.is_empty()).collect();
+    status!("Include dependencies ({} files):", including.len());
+    for (file_path, included) in including {
+        let included: Vec<String> = included.iter().map(|path| relative_path(path)).collect();
+        status!("  {}: {}", relative_path(file_path), included.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn expander() -> IncludeExpander {
+        IncludeExpander::new(Regex::new(r"^-- include: (.+)$").unwrap(), 10, false)
+    }
+
+    #[test]
+    fn test_expand_nested_includes() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("lib"))?;
+        let main = temp_dir.path().join("main.sql");
+        fs::write(&main, "BEGIN;\n-- include: lib/tables.sql\nCOMMIT;\n")?;
+        fs::write(temp_dir.path().join("lib/tables.sql"), "CREATE TABLE a;\n-- include: ../views.sql\n")?;
+        fs::write(temp_dir.path().join("views.sql"), "CREATE VIEW v;\n")?;
+
+        let expander = expander();
+        let content = fs::read_to_string(&main)?;
+        let (expanded, origins) = expander.expand(&main, &content, &lines::source_origins(&content));
+        let tables = temp_dir.path().join("lib/tables.sql");
+        let views = temp_dir.path().join("views.sql");
+        assert_eq!(
+            expanded,
+            format!(
+                "BEGIN;\n==> {} (included from {}:2) <==\nCREATE TABLE a;\n==> {} (included from {}:2) <==\nCREATE VIEW v;\nCOMMIT;\n",
+                relative_path(&tables), relative_path(&main), relative_path(&views), relative_path(&tables)
+            )
+        );
+        assert_eq!(origins, vec![Some(1), None, None, None, None, Some(3)]);
+        assert_eq!(expander.dependencies()[&main], vec![tables, views]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_cycles_and_missing_files_are_kept() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let a = temp_dir.path().join("a.sql");
+        fs::write(&a, "-- include: b.sql\n-- include: missing.sql\n")?;
+        fs::write(temp_dir.path().join("b.sql"), "-- include: ./a.sql\n")?;
+
+        let content = fs::read_to_string(&a)?;
+        let expander = expander();
+        let (expanded, _) = expander.expand(&a, &content, &lines::source_origins(&content));
+        assert!(expanded.contains("(included from"));
+        assert!(expanded.contains("-- include: ./a.sql\n"));
+        assert!(expanded.ends_with("-- include: missing.sql\n"));
+
+        // Reading the input again replaces its warnings rather than adding to them
+        expander.expand(&a, &content, &lines::source_origins(&content));
+        let warnings = &expander.warnings()[&a];
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("Include cycle at ") && warnings[1].starts_with("Cannot include "));
+        Ok(())
+    }
+}
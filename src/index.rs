@@ -0,0 +1,249 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{bundle, includes};
+
+// Where one input landed in the output. Byte ranges are end-exclusive, line
+// ranges are 1-based and inclusive; an empty input has line_end < line_start.
+// Inputs are written whole, so line_start holds line 1 of the input.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub byte_start: u64,
+    pub byte_end: u64,
+    pub line_start: u64,
+    pub line_end: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutputIndex {
+    pub output: String,
+    pub files: Vec<IndexEntry>,
+}
+
+impl OutputIndex {
+    // Source path and line of a 1-based output line
+    pub fn locate(&self, line: u64) -> Option<(&str, u64)> {
+        self.files.iter()
+            .find(|entry| entry.line_start <= line && line <= entry.line_end)
+            .map(|entry| (entry.path.as_str(), line - entry.line_start + 1))
+    }
+}
+
+// Counts the bytes and lines written through it, to index the output as it is
+// produced
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    bytes: u64,
+    newlines: u64,
+    ends_with_newline: bool,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, bytes: 0, newlines: 0, ends_with_newline: true }
+    }
+
+    // Byte offset and 1-based line of the next byte to be written
+    pub fn position(&self) -> (u64, u64) {
+        (self.bytes, self.newlines + 1)
+    }
+
+    // Entry for what was written since start, a value from position()
+    pub fn entry(&self, path: &str, start: (u64, u64)) -> IndexEntry {
+        let (byte_start, line_start) = start;
+        let line_end = if self.bytes == byte_start {
+            line_start - 1
+        } else {
+            self.newlines + if self.ends_with_newline { 0 } else { 1 }
+        };
+        IndexEntry { path: path.to_string(), byte_start, byte_end: self.bytes, line_start, line_end }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let buf = &buf[..written];
+        self.bytes += written as u64;
+        self.newlines += buf.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        if let Some(&last) = buf.last() {
+            self.ends_with_newline = last == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Rebuild the index of an output written with --headers from its headers
+pub fn index_from_bundle(output: &str, bytes: &[u8]) -> Result<OutputIndex> {
+    let mut writer = CountingWriter::new(io::sink());
+    let mut files = Vec::new();
+    for record in bundle::parse_bundle(bytes)? {
+        writer.write_all(bundle::header_line(&record.path, record.content.len(), record.raw).as_bytes())?;
+        let start = writer.position();
+        writer.write_all(&record.content)?;
+        files.push(writer.entry(&record.path, start));
+        writer.write_all(b"\n")?;
+    }
+    Ok(OutputIndex { output: output.to_string(), files })
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_vlq(value: i64, encoded: &mut String) {
+    let mut vlq = if value < 0 { ((-value) << 1) | 1 } else { value << 1 };
+    loop {
+        let mut digit = (vlq & 0b11111) as usize;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 0b100000;
+        }
+        encoded.push(BASE64[digit] as char);
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+// Source map v3 mapping the start of every output line to its source line.
+// Sources are relative to the current directory, and source_root leads there
+// from the directory of the map.
+pub fn source_map(index: &OutputIndex, source_root: &str) -> serde_json::Value {
+    let mut mappings = String::new();
+    // Output line the mappings have reached
+    let mut current = 1;
+    let (mut previous_source, mut previous_line) = (0i64, 0i64);
+    for (source, entry) in index.files.iter().enumerate() {
+        for line in entry.line_start..=entry.line_end {
+            // Lines outside any input, such as headers, have no segment
+            while current < line {
+                mappings.push(';');
+                current += 1;
+            }
+            let source_line = (line - entry.line_start) as i64;
+            // Generated column, source index, source line and source column
+            encode_vlq(0, &mut mappings);
+            encode_vlq(source as i64 - previous_source, &mut mappings);
+            encode_vlq(source_line - previous_line, &mut mappings);
+            encode_vlq(0, &mut mappings);
+            previous_source = source as i64;
+            previous_line = source_line;
+        }
+    }
+
+    serde_json::json!({
+        "version": 3,
+        "file": Path::new(&index.output).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        "sourceRoot": source_root,
+        "sources": index.files.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(),
+        "names": [],
+        "mappings": mappings,
+    })
+}
+
+// Path from the directory of map_path to current_dir, with "/" separators
+// and a trailing "/", or empty when the map is in current_dir
+fn source_root(map_path: &Path, current_dir: &Path) -> String {
+    let map_dir = includes::normalize(&current_dir.join(map_path.parent().unwrap_or(Path::new(""))));
+    let common = map_dir.components().zip(current_dir.components()).take_while(|(map, current)| map == current).count();
+    let up = map_dir.components().skip(common).map(|_| "../".to_string());
+    let down = current_dir.components().skip(common).filter_map(|component| match component {
+        Component::Normal(part) => Some(format!("{}/", part.to_string_lossy())),
+        _ => None,
+    });
+    up.chain(down).collect()
+}
+
+// A .map index is written as a source map, anything else as the JSON index
+pub fn write_index(index_path: &Path, index: &OutputIndex) -> Result<()> {
+    let json = if index_path.extension().is_some_and(|extension| extension == "map") {
+        let root = source_root(index_path, &std::env::current_dir()?);
+        serde_json::to_string(&source_map(index, &root))?
+    } else {
+        serde_json::to_string_pretty(index)?
+    };
+    fs::write(index_path, json + "\n").with_context(|| format!("Failed to write index: {}", index_path.display()))
+}
+
+pub fn read_index(index_path: &Path) -> Result<OutputIndex> {
+    let json = fs::read_to_string(index_path).with_context(|| format!("Failed to read index: {}", index_path.display()))?;
+    serde_json::from_str(&json).with_context(|| format!("Invalid index (source maps cannot be used to locate lines): {}", index_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counting_writer_entries() -> Result<()> {
+        let mut writer = CountingWriter::new(Vec::new());
+        let start = writer.position();
+        writer.write_all(b"a1\na2")?;
+        let first = writer.entry("a.txt", start);
+        writer.write_all(b"\n")?;
+        let start = writer.position();
+        writer.write_all(b"b1\n")?;
+        let second = writer.entry("b.txt", start);
+
+        assert_eq!((first.byte_start, first.byte_end, first.line_start, first.line_end), (0, 5, 1, 2));
+        assert_eq!((second.byte_start, second.byte_end, second.line_start, second.line_end), (6, 9, 3, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_from_bundle_and_locate() -> Result<()> {
+        let mut bytes = bundle::header_line("a.txt", 6, false).into_bytes();
+        bytes.extend_from_slice(b"a1\na2\n\n");
+        bytes.extend_from_slice(bundle::header_line("b.txt", 2, true).as_bytes());
+        bytes.extend_from_slice(b"b1\n");
+
+        let index = index_from_bundle("bundle.txt", &bytes)?;
+        assert_eq!(index.locate(1), None);
+        assert_eq!(index.locate(3), Some(("a.txt", 2)));
+        assert_eq!(index.locate(6), Some(("b.txt", 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map_mappings() {
+        let mut encoded = String::new();
+        for value in [0, 1, -1, 16, 1000] {
+            encode_vlq(value, &mut encoded);
+        }
+        assert_eq!(encoded, "ACDgBw+B");
+
+        let entry = |path: &str, line_start, line_end| IndexEntry {
+            path: path.to_string(), byte_start: 0, byte_end: 0, line_start, line_end,
+        };
+        let index = OutputIndex { output: "out/bundle.js".to_string(), files: vec![entry("a.js", 1, 2), entry("b.js", 3, 3)] };
+        let map = source_map(&index, "../");
+        assert_eq!(map["file"], "bundle.js");
+        assert_eq!(map["sourceRoot"], "../");
+        assert_eq!(map["sources"], serde_json::json!(["a.js", "b.js"]));
+        assert_eq!(map["mappings"], "AAAA;AACA;ACDA");
+
+        // Lines between inputs are left without a segment
+        let index = OutputIndex { output: "bundle.js".to_string(), files: vec![entry("a.js", 2, 2), entry("b.js", 5, 4), entry("c.js", 6, 6)] };
+        assert_eq!(source_map(&index, "")["mappings"], ";AAAA;;;;AEAA");
+    }
+
+    #[test]
+    fn test_source_root() {
+        let current_dir = Path::new("/work/project");
+        assert_eq!(source_root(Path::new("bundle.js.map"), current_dir), "");
+        assert_eq!(source_root(Path::new("dist/maps/bundle.js.map"), current_dir), "../../");
+        assert_eq!(source_root(Path::new("../out/bundle.js.map"), current_dir), "../project/");
+        assert_eq!(source_root(Path::new("/tmp/bundle.js.map"), current_dir), "../work/project/");
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use base64::Engine;
+use indicatif::ProgressBar;
+use serde::Serialize;
+
+use crate::output::OutputFormat;
+use crate::timestamp::{format_rfc3339, unix_seconds};
+use crate::{archive, decode_input, is_binary, read_input_bytes, relative_path, transform_content, ConcatOptions};
+
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub path: String,
+    // Detected text encoding, or "base64" for binary content
+    pub encoding: String,
+    // Size of the input in bytes, before decoding
+    pub size: u64,
+    // Modification time as an RFC 3339 UTC timestamp
+    pub mtime: String,
+    pub content: String,
+}
+
+// The record of an input, or None when --grep or --grep-v kept no line of it
+pub fn file_record(file_path: &Path, bytes: &[u8], options: &ConcatOptions) -> Result<Option<FileRecord>> {
+    let modified = archive::input_metadata(file_path)?.modified()?;
+    let (encoding, content) = if is_binary(bytes) {
+        ("base64".to_string(), base64::engine::general_purpose::STANDARD.encode(bytes))
+    } else {
+        let (content, encoding) = decode_input(file_path, bytes, options);
+        let Some(content) = transform_content(file_path, content, options) else {
+            return Ok(None);
+        };
+        (encoding.to_string(), content)
+    };
+
+    Ok(Some(FileRecord {
+        path: relative_path(file_path),
+        encoding,
+        size: bytes.len() as u64,
+        mtime: format_rfc3339(unix_seconds(modified)),
+        content,
+    }))
+}
+
+// Write one JSON object per input: a pretty-printed array for json, or one
+// compact object per line for jsonl
+pub fn write_json<W: Write>(mut output: W, files: &[PathBuf], options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<W> {
+    let lines = options.format == OutputFormat::Jsonl;
+    if !lines {
+        write!(output, "[")?;
+    }
+
+    let mut written = 0;
+    for file_path in files {
+        let Some(bytes) = read_input_bytes(file_path, options, progress)? else {
+            continue;
+        };
+        let Some(record) = file_record(file_path, &bytes, options)? else {
+            continue;
+        };
+        let json = if lines {
+            serde_json::to_string(&record)
+        } else {
+            serde_json::to_string_pretty(&record)
+        }
+        .with_context(|| format!("Failed to serialize file: {:?}", file_path))?;
+
+        if lines {
+            writeln!(output, "{}", json)?;
+        } else {
+            let separator = if written == 0 { "" } else { "," };
+            // Indent the object inside the array
+            write!(output, "{}\n  {}", separator, json.replace('\n', "\n  "))?;
+        }
+        written += 1;
+    }
+
+    if !lines {
+        writeln!(output, "\n]")?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write_input;
+    use tempfile::TempDir;
+
+    fn write_inputs(temp_dir: &TempDir) -> Result<Vec<PathBuf>> {
+        Ok(vec![
+            write_input(temp_dir, "quote.txt", "He said \"hi\"\n\ttab")?,
+            write_input(temp_dir, "blob.bin", [0x00, 0xFF, 0x10])?,
+        ])
+    }
+
+    #[test]
+    fn test_write_json_array() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+        let options = ConcatOptions { format: OutputFormat::Json, ..Default::default() };
+
+        let output = write_json(Vec::new(), &files, &options, None)?;
+        let value: serde_json::Value = serde_json::from_slice(&output)?;
+        let records = value.as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["content"], "He said \"hi\"\n\ttab");
+        assert_eq!(records[0]["encoding"], "UTF-8");
+        assert_eq!(records[0]["size"], 17);
+        assert!(records[0]["path"].as_str().unwrap().ends_with("quote.txt"));
+        assert!(records[0]["mtime"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(records[1]["encoding"], "base64");
+        assert_eq!(records[1]["content"], "AP8Q");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_jsonl() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+        let options = ConcatOptions { format: OutputFormat::Jsonl, ..Default::default() };
+
+        let output = String::from_utf8(write_json(Vec::new(), &files, &options, None)?)?;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            let record: serde_json::Value = serde_json::from_str(line)?;
+            assert!(record["content"].is_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use regex::Regex;
+
+use crate::relative_path;
+
+// Which lines of each input to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineSelection {
+    Head(usize),
+    Tail(usize),
+    // 1-based, inclusive; an open end runs to the last line
+    Range { start: usize, end: Option<usize> },
+}
+
+// Parse a range such as "10:200", "10:" or ":200"
+pub fn parse_range(value: &str) -> Result<LineSelection, String> {
+    let invalid = || format!("invalid line range: {} (expected START:END, START: or :END)", value);
+    let (start, end) = value.split_once(':').ok_or_else(invalid)?;
+    let start = match start.trim() {
+        "" => 1,
+        start => start.parse::<usize>().ok().filter(|&start| start > 0).ok_or_else(invalid)?,
+    };
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse::<usize>().ok().filter(|&end| end >= start).ok_or_else(invalid)?),
+    };
+    Ok(LineSelection::Range { start, end })
+}
+
+pub fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|error| error.to_string())
+}
+
+// Source line of each line of transformed content, from 1; None for lines
+// the transformations added, such as elision markers and included files
+pub type Origins = Vec<Option<usize>>;
+
+// Origins of content as read, before any transformation
+pub fn source_origins(content: &str) -> Origins {
+    (1..=content.split_inclusive('\n').count()).map(Some).collect()
+}
+
+// Origins once the newlines of text at the given byte offsets are replaced,
+// each joining the line after it to the one before
+pub fn join_lines(text: &str, origins: &[Option<usize>], joined: &[usize]) -> Origins {
+    let mut kept: Origins = origins.first().copied().into_iter().collect();
+    for (line, (offset, _)) in text.match_indices('\n').enumerate() {
+        if line + 1 < origins.len() && !joined.contains(&offset) {
+            kept.push(origins[line + 1]);
+        }
+    }
+    kept
+}
+
+fn elision_marker(omitted: usize) -> String {
+    format!("... ({} lines omitted) ...\n", omitted)
+}
+
+// Keep the selected lines of content, optionally marking where lines were cut
+pub fn select_lines(content: &str, origins: &[Option<usize>], selection: LineSelection, elide: bool) -> (String, Origins) {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let total = lines.len();
+    // Zero-based, end-exclusive bounds of the kept lines
+    let (start, end) = match selection {
+        LineSelection::Head(count) => (0, count.min(total)),
+        LineSelection::Tail(count) => (total.saturating_sub(count), total),
+        LineSelection::Range { start, end } => {
+            let end = end.unwrap_or(total).min(total);
+            ((start - 1).min(end), end)
+        }
+    };
+    if start == 0 && end == total {
+        return (content.to_string(), origins.to_vec());
+    }
+
+    let mut selected = String::new();
+    let mut kept = Origins::new();
+    if elide && start > 0 {
+        selected.push_str(&elision_marker(start));
+        kept.push(None);
+    }
+    selected.push_str(&lines[start..end].concat());
+    kept.extend_from_slice(&origins[start..end]);
+    if elide && end < total {
+        if !selected.is_empty() && !selected.ends_with('\n') {
+            selected.push('\n');
+        }
+        selected.push_str(&elision_marker(total - end));
+        kept.push(None);
+    }
+    (selected, kept)
+}
+
+// Line-level grep applied while concatenating
+#[derive(Debug, Clone, Default)]
+pub struct LineFilter {
+    // Keep only lines matching this pattern
+    pub grep: Option<Regex>,
+    // Drop lines matching this pattern
+    pub grep_v: Option<Regex>,
+    // Context lines kept before and after each kept line
+    pub before: usize,
+    pub after: usize,
+    // Start lines with "path:lineno:", or "path-lineno-" for context lines
+    pub prefix: bool,
+    // Inputs left without any line, recorded per path as they are read so
+    // they can be skipped entirely
+    pub unmatched: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+impl LineFilter {
+    fn keeps(&self, line: &str) -> bool {
+        let line = line.trim_end_matches(['\n', '\r']);
+        self.grep.as_ref().is_none_or(|pattern| pattern.is_match(line))
+            && !self.grep_v.as_ref().is_some_and(|pattern| pattern.is_match(line))
+    }
+
+    // The kept lines of content with their context, or None when no line is
+    // kept so the whole file can be skipped. Non-adjacent groups are separated
+    // by "--" when context is requested, as grep does. Prefixes number lines
+    // by their origins, so they match the source file.
+    pub fn apply(&self, name: &str, content: &str, origins: &[Option<usize>]) -> Option<(String, Origins)> {
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+        let kept: Vec<bool> = lines.iter().map(|line| self.keeps(line)).collect();
+        if !kept.contains(&true) {
+            return None;
+        }
+
+        let mut shown = vec![false; lines.len()];
+        for (index, _) in kept.iter().enumerate().filter(|(_, kept)| **kept) {
+            let start = index.saturating_sub(self.before);
+            let end = (index + self.after + 1).min(lines.len());
+            shown[start..end].iter_mut().for_each(|shown| *shown = true);
+        }
+
+        let mut filtered = String::new();
+        let mut filtered_origins = Origins::new();
+        let mut previous: Option<usize> = None;
+        for (index, line) in lines.iter().enumerate().filter(|(index, _)| shown[*index]) {
+            if (self.before > 0 || self.after > 0) && previous.is_some_and(|previous| previous + 1 != index) {
+                filtered.push_str("--\n");
+                filtered_origins.push(None);
+            }
+            if self.prefix {
+                let separator = if kept[index] { ':' } else { '-' };
+                let lineno = origins[index].map_or(String::new(), |lineno| lineno.to_string());
+                filtered.push_str(&format!("{name}{separator}{lineno}{separator}"));
+            }
+            filtered_origins.push(origins[index]);
+            filtered.push_str(line);
+            if !line.ends_with('\n') {
+                filtered.push('\n');
+            }
+            previous = Some(index);
+        }
+        Some((filtered, filtered_origins))
+    }
+
+    // apply to the whole content of an input, recording whether any line of
+    // it was kept
+    pub fn apply_to_file(&self, file_path: &Path, content: &str, origins: &[Option<usize>]) -> Option<(String, Origins)> {
+        let filtered = self.apply(&relative_path(file_path), content, origins);
+        let mut unmatched = self.unmatched.lock().unwrap();
+        if filtered.is_some() {
+            unmatched.remove(file_path);
+        } else {
+            unmatched.insert(file_path.to_path_buf());
+        }
+        filtered
+    }
+
+    pub fn unmatched(&self) -> BTreeSet<PathBuf> {
+        self.unmatched.lock().unwrap().clone()
+    }
+}
+
+// How --number counts lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Numbering {
+    // One count running across all files, as `cat -n` does
+    Global,
+    // Restart at 1 for every file
+    PerFile,
+}
+
+impl Numbering {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "global" => Ok(Numbering::Global),
+            "file" => Ok(Numbering::PerFile),
+            _ => Err(anyhow::anyhow!("Unknown numbering mode: {}", name)),
+        }
+    }
+}
+
+const PLACEHOLDERS: [&str; 5] = ["{path}", "{relpath}", "{name}", "{index}", "{lineno}"];
+
+// A line prefix template with the placeholders of one input expanded, split
+// around {lineno}, the only one that changes from line to line
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTemplate(Vec<String>);
+
+impl FileTemplate {
+    // Expand {path} as resolved, {relpath}, {name} (file name) and {index}
+    // (1-based file position) in a single scan, so a placeholder inside an
+    // expanded value is kept as it is
+    pub fn new(template: &str, file_path: &Path, index: usize) -> Self {
+        let mut parts = vec![String::new()];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let part = parts.last_mut().unwrap();
+            part.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(placeholder) = PLACEHOLDERS.iter().find(|placeholder| rest.starts_with(**placeholder)) else {
+                part.push('{');
+                rest = &rest[1..];
+                continue;
+            };
+            match *placeholder {
+                "{path}" => part.push_str(&file_path.display().to_string()),
+                "{relpath}" => part.push_str(&relative_path(file_path)),
+                "{name}" => part.push_str(&file_path.file_name().unwrap_or_default().to_string_lossy()),
+                "{index}" => part.push_str(&index.to_string()),
+                _ => parts.push(String::new()),
+            }
+            rest = &rest[placeholder.len()..];
+        }
+        parts.last_mut().unwrap().push_str(rest);
+        FileTemplate(parts)
+    }
+
+    // The prefix of a line, with {lineno} as its line in the source file, or
+    // empty for added lines
+    pub fn render(&self, lineno: Option<usize>) -> String {
+        self.0.join(&lineno.map_or(String::new(), |lineno| lineno.to_string()))
+    }
+}
+
+// Prefixes every line with a number and/or an expanded template; keeps the
+// global line count across files. Lines the transformations added, such as
+// elision markers, "--" separators and included files, are left unnumbered
+// in both modes: global numbers count the lines that come from the inputs.
+#[derive(Debug)]
+pub struct LinePrefixer<'a> {
+    template: Option<&'a str>,
+    numbering: Option<Numbering>,
+    lines: usize,
+}
+
+impl<'a> LinePrefixer<'a> {
+    pub fn new(template: Option<&'a str>, numbering: Option<Numbering>) -> Self {
+        LinePrefixer { template, numbering, lines: 0 }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.template.is_some() || self.numbering.is_some()
+    }
+
+    // The template expanded for the file at 1-based position index among the
+    // inputs, to pass to prefix for each of its lines
+    pub fn file_template(&self, file_path: &Path, index: usize) -> Option<FileTemplate> {
+        self.template.map(|template| FileTemplate::new(template, file_path, index))
+    }
+
+    // Prefix for the next output line, which is line lineno of its file, or
+    // was added to it
+    pub fn prefix(&mut self, template: Option<&FileTemplate>, lineno: Option<usize>) -> String {
+        let number = match (self.numbering, lineno) {
+            (Some(Numbering::Global), Some(_)) => {
+                self.lines += 1;
+                Some(self.lines)
+            }
+            (Some(Numbering::PerFile), lineno) => lineno,
+            _ => None,
+        };
+        let mut prefix = match self.numbering {
+            Some(_) => format!("{:>6}\t", number.map_or(String::new(), |number| number.to_string())),
+            None => String::new(),
+        };
+        if let Some(template) = template {
+            prefix.push_str(&template.render(lineno));
+        }
+        prefix
+    }
+
+    pub fn apply(&mut self, file_path: &Path, index: usize, content: &str, origins: &[Option<usize>]) -> String {
+        if !self.is_active() {
+            return content.to_string();
+        }
+        let template = self.file_template(file_path, index);
+        let mut prefixed = String::with_capacity(content.len());
+        for (line_index, line) in content.split_inclusive('\n').enumerate() {
+            prefixed.push_str(&self.prefix(template.as_ref(), origins.get(line_index).copied().flatten()));
+            prefixed.push_str(line);
+        }
+        prefixed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "1\n2\n3\n4\n5\n";
+
+    fn select(content: &str, selection: LineSelection, elide: bool) -> String {
+        select_lines(content, &source_origins(content), selection, elide).0
+    }
+
+    fn filter_lines(filter: &LineFilter, content: &str) -> Option<String> {
+        filter.apply("app.log", content, &source_origins(content)).map(|(filtered, _)| filtered)
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("10:200"), Ok(LineSelection::Range { start: 10, end: Some(200) }));
+        assert_eq!(parse_range("10:"), Ok(LineSelection::Range { start: 10, end: None }));
+        assert_eq!(parse_range(":5"), Ok(LineSelection::Range { start: 1, end: Some(5) }));
+        assert!(parse_range("0:5").is_err());
+        assert!(parse_range("5:2").is_err());
+        assert!(parse_range("12").is_err());
+    }
+
+    #[test]
+    fn test_select_lines() {
+        assert_eq!(select(CONTENT, LineSelection::Head(2), false), "1\n2\n");
+        assert_eq!(select(CONTENT, LineSelection::Tail(2), false), "4\n5\n");
+        assert_eq!(select(CONTENT, LineSelection::Range { start: 2, end: Some(3) }, false), "2\n3\n");
+        assert_eq!(select(CONTENT, LineSelection::Head(10), true), CONTENT);
+        assert_eq!(select("a\nb", LineSelection::Tail(1), false), "b");
+    }
+
+    #[test]
+    fn test_select_lines_with_elision() {
+        assert_eq!(select(CONTENT, LineSelection::Head(2), true), "1\n2\n... (3 lines omitted) ...\n");
+        assert_eq!(select(CONTENT, LineSelection::Tail(1), true), "... (4 lines omitted) ...\n5\n");
+        assert_eq!(
+            select(CONTENT, LineSelection::Range { start: 3, end: Some(3) }, true),
+            "... (2 lines omitted) ...\n3\n... (2 lines omitted) ...\n"
+        );
+    }
+
+    #[test]
+    fn test_line_filter() {
+        let filter = LineFilter { grep: Some(Regex::new("ERROR").unwrap()), ..Default::default() };
+        let log = "INFO start\nERROR one\nINFO middle\nINFO more\nERROR two";
+        assert_eq!(filter_lines(&filter, log), Some("ERROR one\nERROR two\n".to_string()));
+        assert_eq!(filter_lines(&filter, "INFO only\n"), None);
+
+        let filter = LineFilter { grep_v: Some(Regex::new("^INFO").unwrap()), prefix: true, ..Default::default() };
+        assert_eq!(filter_lines(&filter, log), Some("app.log:2:ERROR one\napp.log:5:ERROR two\n".to_string()));
+    }
+
+    #[test]
+    fn test_line_filter_context() {
+        let filter = LineFilter { grep: Some(Regex::new("ERROR").unwrap()), before: 1, prefix: true, ..Default::default() };
+        let log = "INFO start\nERROR one\nINFO middle\nINFO more\nERROR two\n";
+        assert_eq!(
+            filter_lines(&filter, log),
+            Some("app.log-1-INFO start\napp.log:2:ERROR one\n--\napp.log-4-INFO more\napp.log:5:ERROR two\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_line_filter_numbers_source_lines() {
+        // --tail 2 --grep ERROR reports the line in the file, not in the tail
+        let (tail, origins) = select_lines(CONTENT, &source_origins(CONTENT), LineSelection::Tail(2), true);
+        assert_eq!(origins, vec![None, Some(4), Some(5)]);
+        let filter = LineFilter { grep: Some(Regex::new("5").unwrap()), prefix: true, ..Default::default() };
+        assert_eq!(filter.apply("app.log", &tail, &origins), Some(("app.log:5:5\n".to_string(), vec![Some(5)])));
+
+        assert_eq!(join_lines("a\nb\nc\n", &source_origins("a\nb\nc\n"), &[1]), vec![Some(1), Some(3)]);
+    }
+
+    #[test]
+    fn test_line_prefixer() {
+        let file_path = Path::new("/logs/app.log");
+        let mut prefixer = LinePrefixer::new(Some("{name}#{index}:{lineno}: "), None);
+        assert_eq!(prefixer.apply(file_path, 2, "a\nb", &source_origins("a\nb")), "app.log#2:1: a\napp.log#2:2: b");
+
+        let mut global = LinePrefixer::new(None, Some(Numbering::Global));
+        assert_eq!(global.apply(file_path, 1, "a\nb\n", &source_origins("a\nb\n")), "     1\ta\n     2\tb\n");
+        assert_eq!(global.apply(file_path, 2, "c\n", &source_origins("c\n")), "     3\tc\n");
+
+        let mut per_file = LinePrefixer::new(None, Some(Numbering::PerFile));
+        per_file.apply(file_path, 1, "a\nb\n", &source_origins("a\nb\n"));
+        assert_eq!(per_file.apply(file_path, 2, "c\n", &[Some(4)]), "     4\tc\n");
+
+        // Placeholders in the path are not expanded again
+        let odd = Path::new("/logs/{lineno}-{index}.log");
+        assert_eq!(FileTemplate::new("{name}|{lineno}|{x}", odd, 3).render(Some(7)), "{lineno}-{index}.log|7|{x}");
+    }
+
+    #[test]
+    fn test_numbering_skips_added_lines() {
+        // --grep ERROR -A 1 on two files: "--" separators are not numbered
+        let filter = LineFilter { grep: Some(Regex::new("ERROR").unwrap()), after: 1, ..Default::default() };
+        let log = "ERROR a\nINFO b\nINFO c\nERROR d\n";
+        let (filtered, origins) = filter.apply("app.log", log, &source_origins(log)).unwrap();
+        assert_eq!(origins, vec![Some(1), Some(2), None, Some(4)]);
+
+        let file_path = Path::new("app.log");
+        let mut global = LinePrefixer::new(None, Some(Numbering::Global));
+        assert_eq!(global.apply(file_path, 1, &filtered, &origins), "     1\tERROR a\n     2\tINFO b\n      \t--\n     3\tERROR d\n");
+        assert_eq!(global.apply(file_path, 2, &filtered, &origins), "     4\tERROR a\n     5\tINFO b\n      \t--\n     6\tERROR d\n");
+
+        let mut per_file = LinePrefixer::new(Some("{index}:"), Some(Numbering::PerFile));
+        assert_eq!(per_file.apply(file_path, 2, &filtered, &origins), "     1\t2:ERROR a\n     2\t2:INFO b\n      \t2:--\n     4\t2:ERROR d\n");
+    }
+}
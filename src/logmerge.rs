@@ -0,0 +1,434 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use encoding_rs::{Decoder, Encoding};
+use indicatif::ProgressBar;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::{advance_progress, archive, detect_encoding, failures, DETECTION_BYTES, lines, manifest, read_numbered_input, transforms_content, ConcatOptions};
+
+static ISO_8601: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?").unwrap()
+});
+static SYSLOG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[A-Z][a-z]{2} +\d{1,2} \d{2}:\d{2}:\d{2}").unwrap()
+});
+static EPOCH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\d{13}|\d{10}(?:\.\d{1,9})?)\b").unwrap()
+});
+
+// How the leading timestamp of a log line is recognized
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimestampFormat {
+    // 2024-05-01T12:00:00.123Z, 2024-05-01 12:00:00+02:00, ...
+    Iso8601,
+    // May  1 12:00:00; the year is not logged, so all lines share one
+    Syslog,
+    // Seconds (with optional fraction) or milliseconds since the Unix epoch
+    Epoch,
+    // A chrono strftime format such as "%d/%b/%Y:%H:%M:%S %z"
+    Custom(String),
+}
+
+impl TimestampFormat {
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "iso8601" | "iso" => TimestampFormat::Iso8601,
+            "syslog" => TimestampFormat::Syslog,
+            "epoch" => TimestampFormat::Epoch,
+            format => TimestampFormat::Custom(format.to_string()),
+        }
+    }
+
+    // Nanoseconds since the epoch of the timestamp starting text, in UTC when
+    // the timestamp carries an offset
+    fn parse_leading(&self, text: &str) -> Option<i64> {
+        match self {
+            TimestampFormat::Iso8601 => {
+                let timestamp = ISO_8601.find(text)?.as_str().replacen(' ', "T", 1).replace(',', ".");
+                if let Ok(datetime) = DateTime::parse_from_rfc3339(&timestamp) {
+                    return datetime.timestamp_nanos_opt();
+                }
+                // Offsets written as +02:00 or +0200
+                for format in ["%Y-%m-%dT%H:%M:%S%.f%:z", "%Y-%m-%dT%H:%M:%S%.f%z"] {
+                    if let Ok(datetime) = DateTime::parse_from_str(&timestamp, format) {
+                        return datetime.timestamp_nanos_opt();
+                    }
+                }
+                // Without an offset the time is taken as UTC
+                NaiveDateTime::parse_from_str(timestamp.trim_end_matches('Z'), "%Y-%m-%dT%H:%M:%S%.f")
+                    .ok()?
+                    .and_utc()
+                    .timestamp_nanos_opt()
+            }
+            TimestampFormat::Syslog => {
+                let timestamp = SYSLOG.find(text)?.as_str();
+                NaiveDateTime::parse_from_str(&format!("2000 {}", timestamp), "%Y %b %e %H:%M:%S")
+                    .ok()?
+                    .and_utc()
+                    .timestamp_nanos_opt()
+            }
+            TimestampFormat::Epoch => {
+                let timestamp = EPOCH.captures(text)?.get(1)?.as_str();
+                if timestamp.len() == 13 && !timestamp.contains('.') {
+                    return timestamp.parse::<i64>().ok()?.checked_mul(1_000_000);
+                }
+                let (seconds, fraction) = timestamp.split_once('.').unwrap_or((timestamp, ""));
+                let nanos = format!("{:0<9}", fraction).parse::<i64>().ok()?;
+                seconds.parse::<i64>().ok()?.checked_mul(1_000_000_000)?.checked_add(nanos)
+            }
+            TimestampFormat::Custom(format) => {
+                if let Ok((datetime, _)) = DateTime::parse_and_remainder(text, format) {
+                    return datetime.timestamp_nanos_opt();
+                }
+                if let Ok((datetime, _)) = NaiveDateTime::parse_and_remainder(text, format) {
+                    return datetime.and_utc().timestamp_nanos_opt();
+                }
+                let (date, _) = NaiveDate::parse_and_remainder(text, format).ok()?;
+                date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_nanos_opt()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimestampSettings {
+    pub format: TimestampFormat,
+    // Where the timestamp is in the line: the first capture group, or the
+    // whole match; by default the line must start with it
+    pub regex: Option<Regex>,
+}
+
+impl TimestampSettings {
+    pub fn timestamp(&self, line: &str) -> Option<i64> {
+        match &self.regex {
+            Some(regex) => {
+                let captures = regex.captures(line)?;
+                let text = captures.get(1).or_else(|| captures.get(0))?.as_str();
+                self.format.parse_leading(text)
+            }
+            None => self.format.parse_leading(line),
+        }
+    }
+}
+
+// Bytes read from an input at a time; the first chunk is all the encoding is
+// detected from
+const CHUNK_SIZE: usize = DETECTION_BYTES;
+
+// Lines of an input, decoded as they are read so that only a chunk of each
+// input is held in memory
+struct LineReader {
+    reader: Box<dyn Read>,
+    decoder: Decoder,
+    encoding: &'static Encoding,
+    // Decoded text, of which the first `consumed` bytes were handed out
+    decoded: String,
+    consumed: usize,
+    finished: bool,
+    bytes_in: u64,
+    bytes_out: u64,
+    elapsed: Duration,
+    // Hash of the bytes read, for --manifest
+    hasher: Option<Sha256>,
+}
+
+impl LineReader {
+    // Without an encoding, it is detected from the first chunk the way whole
+    // inputs are. Unlike whole inputs, later bytes cannot change that choice:
+    // those the encoding cannot decode are replaced with U+FFFD.
+    fn new(reader: Box<dyn Read>, encoding: Option<&'static Encoding>, hash: bool) -> Result<Self> {
+        let mut lines = LineReader {
+            reader,
+            decoder: encoding_rs::UTF_8.new_decoder(),
+            encoding: encoding_rs::UTF_8,
+            decoded: String::new(),
+            consumed: 0,
+            finished: false,
+            bytes_in: 0,
+            bytes_out: 0,
+            elapsed: Duration::ZERO,
+            hasher: hash.then(Sha256::new),
+        };
+        let head = lines.read_chunk()?;
+        lines.encoding = encoding.unwrap_or_else(|| detect_encoding(&head));
+        lines.decoder = lines.encoding.new_decoder_with_bom_removal();
+        lines.decode(&head);
+        Ok(lines)
+    }
+
+    fn read_chunk(&mut self) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let read = (&mut self.reader).take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        self.finished = read < CHUNK_SIZE;
+        self.bytes_in += read as u64;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&chunk);
+        }
+        self.elapsed += start.elapsed();
+        Ok(chunk)
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        let start = Instant::now();
+        self.decoded.drain(..self.consumed);
+        self.consumed = 0;
+        self.decoded.reserve(self.decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3));
+        let _ = self.decoder.decode_to_string(bytes, &mut self.decoded, self.finished);
+        self.elapsed += start.elapsed();
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>> {
+        loop {
+            let rest = &self.decoded[self.consumed..];
+            let end = match rest.find('\n') {
+                Some(newline) => newline + 1,
+                None if self.finished && !rest.is_empty() => rest.len(),
+                None if self.finished => return Ok(None),
+                None => {
+                    let chunk = self.read_chunk()?;
+                    self.decode(&chunk);
+                    continue;
+                }
+            };
+            let line = rest[..end].to_string();
+            self.consumed += end;
+            self.bytes_out += end as u64;
+            return Ok(Some(line));
+        }
+    }
+}
+
+// A timestamped line and the lines without a timestamp that follow it, such as
+// the rest of a stack trace
+struct Record {
+    timestamp: i64,
+    // 1-based line number of the first line in its file
+    lineno: usize,
+    lines: Vec<String>,
+}
+
+struct Source<'a> {
+    file_path: &'a Path,
+    // 1-based position of the input among the inputs
+    number: usize,
+    lines: LineReader,
+    // Read and decoded here rather than by read_numbered_input
+    streamed: bool,
+    // Source line of each line of a transformed input
+    origins: Option<lines::Origins>,
+    // Lines read so far, and the first line of the next record
+    lineno: usize,
+    next: Option<String>,
+    pending: Option<Record>,
+}
+
+impl Source<'_> {
+    // Line lineno as read, in the source file
+    fn source_line(&self, lineno: usize) -> Option<usize> {
+        match &self.origins {
+            Some(origins) => origins.get(lineno - 1).copied().flatten(),
+            None => Some(lineno),
+        }
+    }
+
+    fn next_line(&mut self, options: &ConcatOptions) -> Result<Option<String>> {
+        if let Some(line) = self.next.take() {
+            return Ok(Some(line));
+        }
+        let line = self.lines.next_line()
+            .with_context(|| format!("Failed to read file: {:?}", self.file_path))
+            .map(|line| line.inspect(|_| self.lineno += 1));
+        // With --keep-going an input that fails midway ends where it failed
+        failures::skip_on_error(line, self.file_path, options.keep_going.as_ref())
+    }
+
+    fn next_record(&mut self, settings: &TimestampSettings, options: &ConcatOptions) -> Result<Option<Record>> {
+        let Some(line) = self.next_line(options)? else {
+            return Ok(None);
+        };
+        // Lines before the first timestamp of a file sort first
+        let timestamp = settings.timestamp(&line).unwrap_or(i64::MIN);
+        let mut record = Record { timestamp, lineno: self.lineno, lines: vec![line] };
+        while let Some(line) = self.next_line(options)? {
+            if settings.timestamp(&line).is_some() {
+                self.next = Some(line);
+                break;
+            }
+            record.lines.push(line);
+        }
+        Ok(Some(record))
+    }
+}
+
+// Open an input for merging. Inputs are streamed unless options that need the
+// whole file apply, in which case they are read and transformed in memory.
+fn open_source<'a>(file_path: &'a Path, number: usize, options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Option<Source<'a>>> {
+    let (lines, origins) = if transforms_content(file_path, options) {
+        let Some((content, origins)) = read_numbered_input(file_path, options, progress)? else {
+            return Ok(None);
+        };
+        (LineReader::new(Box::new(Cursor::new(content.into_bytes())), Some(encoding_rs::UTF_8), false)?, Some(origins))
+    } else {
+        advance_progress(file_path, progress);
+        let lines = archive::open_input(file_path, options.decompress)
+            .and_then(|reader| LineReader::new(reader, None, options.manifest.is_some()))
+            .with_context(|| format!("Failed to read file: {:?}", file_path))
+            .map(Some);
+        let Some(lines) = failures::skip_on_error(lines, file_path, options.keep_going.as_ref())? else {
+            return Ok(None);
+        };
+        (lines, None)
+    };
+    let streamed = origins.is_none();
+    Ok(Some(Source { file_path, number, lines, streamed, origins, lineno: 0, next: None, pending: None }))
+}
+
+// Interleave the lines of all inputs in timestamp order with a k-way merge:
+// only the next record of each input is compared, and each input keeps its own
+// order. Equal timestamps keep input order.
+pub fn write_merged_logs<W: Write>(mut output: W, files: &[PathBuf], options: &ConcatOptions, settings: &TimestampSettings, progress: Option<&ProgressBar>) -> Result<W> {
+    let mut sources = Vec::with_capacity(files.len());
+    for (index, file_path) in files.iter().enumerate() {
+        if let Some(source) = open_source(file_path, index + 1, options, progress)? {
+            sources.push(source);
+        }
+    }
+
+    let mut heap = BinaryHeap::new();
+    for (index, source) in sources.iter_mut().enumerate() {
+        if let Some(record) = source.next_record(settings, options)? {
+            heap.push(Reverse((record.timestamp, index)));
+            source.pending = Some(record);
+        }
+    }
+
+    let mut prefixer = lines::LinePrefixer::new(options.prefix_lines.as_deref(), options.number);
+    let templates: Vec<Option<lines::FileTemplate>> = sources.iter().map(|source| prefixer.file_template(source.file_path, source.number)).collect();
+    while let Some(Reverse((_, index))) = heap.pop() {
+        let source = &mut sources[index];
+        let record = source.pending.take().expect("a queued source has a pending record");
+        for (offset, line) in record.lines.iter().enumerate() {
+            if prefixer.is_active() {
+                let lineno = source.source_line(record.lineno + offset);
+                output.write_all(prefixer.prefix(templates[index].as_ref(), lineno).as_bytes())?;
+            }
+            output.write_all(line.as_bytes())?;
+            if !line.ends_with('\n') {
+                writeln!(output)?;
+            }
+        }
+        if let Some(record) = source.next_record(settings, options)? {
+            heap.push(Reverse((record.timestamp, index)));
+            source.pending = Some(record);
+        }
+    }
+
+    for source in sources.into_iter().filter(|source| source.streamed) {
+        let lines = source.lines;
+        if let Some(recorder) = &options.report {
+            recorder.record_streamed(source.file_path, lines.encoding.name(), lines.bytes_in, lines.bytes_out, lines.elapsed);
+        }
+        if let (Some(recorder), Some(hasher)) = (&options.manifest, lines.hasher) {
+            recorder.record_digest(source.file_path, lines.bytes_in, lines.encoding.name(), manifest::hex(&hasher.finalize()))?;
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn settings(format: &str) -> TimestampSettings {
+        TimestampSettings { format: TimestampFormat::parse(format), regex: None }
+    }
+
+    #[test]
+    fn test_timestamp_presets() {
+        let iso = settings("iso8601");
+        assert_eq!(iso.timestamp("2024-05-01T12:00:00Z start"), iso.timestamp("2024-05-01T14:00:00+02:00 same"));
+        assert!(iso.timestamp("2024-05-01 12:00:00,5 x") > iso.timestamp("2024-05-01 12:00:00 x"));
+        assert_eq!(iso.timestamp("  at Foo.bar()"), None);
+        assert_eq!(iso.timestamp("2024-05-01T14:00:00+0200 x"), iso.timestamp("2024-05-01T12:00:00Z"));
+        assert_eq!(iso.timestamp("2024-05-01 14:00:00.123+0200 x"), iso.timestamp("2024-05-01T12:00:00.123Z"));
+        assert_eq!(iso.timestamp("2024-05-01T10:00:00-02:00 x"), iso.timestamp("2024-05-01T12:00:00Z"));
+
+        let epoch = settings("epoch");
+        assert_eq!(epoch.timestamp("1700000000.5 x"), Some(1_700_000_000_500_000_000));
+        assert_eq!(epoch.timestamp("1700000000500 x"), Some(1_700_000_000_500_000_000));
+
+        let syslog = settings("syslog");
+        assert!(syslog.timestamp("May  1 12:00:01 host app: x") > syslog.timestamp("May  1 12:00:00 host app: y"));
+
+        let custom = settings("%d/%b/%Y:%H:%M:%S %z");
+        assert_eq!(custom.timestamp("01/May/2024:12:00:00 +0000 GET /"), iso.timestamp("2024-05-01T12:00:00Z"));
+
+        let bracketed = TimestampSettings { format: TimestampFormat::Iso8601, regex: Some(Regex::new(r"^\[([^\]]+)\]").unwrap()) };
+        assert_eq!(bracketed.timestamp("[2024-05-01T12:00:00Z] x"), iso.timestamp("2024-05-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn test_merge_by_timestamp() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let host1 = temp_dir.path().join("host1.log");
+        let host2 = temp_dir.path().join("host2.log");
+        fs::write(&host1, "2024-05-01T12:00:00Z a1\n2024-05-01T12:00:02Z a2 failed\n  at main()\n2024-05-01T12:00:04Z a3\n")?;
+        fs::write(&host2, "2024-05-01T12:00:01Z b1\n2024-05-01T12:00:02Z b2\n2024-05-01T12:00:03Z b3")?;
+
+        let options = ConcatOptions { prefix_lines: Some("{name}: ".to_string()), ..Default::default() };
+        let output = write_merged_logs(Vec::new(), &[host1, host2], &options, &settings("iso8601"), None)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "host1.log: 2024-05-01T12:00:00Z a1\n\
+             host2.log: 2024-05-01T12:00:01Z b1\n\
+             host1.log: 2024-05-01T12:00:02Z a2 failed\n\
+             host1.log:   at main()\n\
+             host2.log: 2024-05-01T12:00:02Z b2\n\
+             host2.log: 2024-05-01T12:00:03Z b3\n\
+             host1.log: 2024-05-01T12:00:04Z a3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_streams_large_and_utf16_inputs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let even = temp_dir.path().join("even.log");
+        let odd = temp_dir.path().join("odd.log");
+        // Both inputs span several chunks
+        let lines = |first: u64| (0..5000).map(|step| format!("{} line {}\n", 1_700_000_000 + first + 2 * step, first + 2 * step)).collect::<String>();
+        fs::write(&even, lines(0))?;
+        let utf16: Vec<u8> = lines(1).encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+        fs::write(&odd, [vec![0xFF, 0xFE], utf16].concat())?;
+
+        let output = write_merged_logs(Vec::new(), &[even, odd], &ConcatOptions::default(), &settings("epoch"), None)?;
+        let output = String::from_utf8(output)?;
+        let expected: String = (0..10000).map(|second| format!("{} line {}\n", 1_700_000_000 + second, second)).collect();
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_detects_encoding_from_first_chunk() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let log = temp_dir.path().join("app.log");
+        // ASCII for the whole first chunk, then a Latin-1 "é"
+        let mut bytes = "1700000000 ascii\n".repeat(CHUNK_SIZE / 17 + 1).into_bytes();
+        bytes.extend_from_slice(b"1700000001 caf\xe9\n");
+        fs::write(&log, bytes)?;
+
+        let output = write_merged_logs(Vec::new(), &[log], &ConcatOptions::default(), &settings("epoch"), None)?;
+        assert!(String::from_utf8(output)?.ends_with("1700000001 caf\u{FFFD}\n"));
+        Ok(())
+    }
+}
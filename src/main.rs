@@ -5,19 +5,172 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 
+// Set when stdout carries the JSON run report (--report -), so the
+// human-readable messages move to stderr
+static STATUS_TO_STDERR: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// println! for progress and summary messages
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if crate::STATUS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod archive;
+mod bundle;
+mod failures;
+mod fingerprint;
+mod follow;
+mod includes;
+mod index;
+mod json;
+mod lines;
+mod logmerge;
+mod manifest;
+mod markdown;
+mod merge;
+mod output;
+mod pack;
+mod parts;
+mod redact;
+mod report;
+mod strip;
+mod table;
+#[cfg(test)]
+mod testutil;
+mod timestamp;
+mod tokens;
+mod watch;
+
+use output::{CompressionSettings, OutputFormat, OutputWriter};
+
+// Options controlling how the resolved files are read and written
+#[derive(Debug, Default, Clone)]
+struct ConcatOptions {
+    // Transparently decompress single-file .gz, .bz2, .xz and .zst inputs
+    decompress: bool,
+    // Record per-input sizes, encodings and reading time for --report
+    report: Option<report::RunRecorder>,
+    // Record each input's size, encoding and hash as it is read, for --manifest
+    manifest: Option<manifest::InputRecorder>,
+    // Skip unreadable inputs instead of failing, recording why
+    keep_going: Option<failures::Failures>,
+    // Compress the output stream (gzip, bzip2, xz or zstd)
+    compression: Option<CompressionSettings>,
+    // Shape of the output: a flat text file or an archive of the inputs
+    format: OutputFormat,
+    // Parse the inputs as JSON, JSON Lines or YAML and merge them into one valid document
+    merge: Option<merge::MergeSettings>,
+    // Concatenate CSV/TSV inputs under a single header row
+    csv: Option<table::HeaderMismatch>,
+    // Interleave the lines of all inputs in order of their leading timestamp
+    merge_by_timestamp: Option<logmerge::TimestampSettings>,
+    // Precede each file with a length-prefixed header so the output can be split again
+    headers: bool,
+    // Write a sidecar JSON index (or a source map for .map) of where each input landed
+    index: Option<PathBuf>,
+    // Add to the end of an existing output instead of replacing it
+    append: bool,
+    // Write the output to a temporary file renamed over it once complete
+    atomic: bool,
+    // Roll the output over into numbered parts after these limits
+    split: Option<parts::SplitSettings>,
+    // Start markdown output with a directory tree of the inputs
+    tree: bool,
+    // Leave out the content of larger files in markdown output
+    max_file_size: Option<u64>,
+    // Inline the files named by include directives
+    includes: Option<includes::IncludeExpander>,
+    // Replace secrets in the decoded content with [REDACTED]
+    redactor: Option<redact::Redactor>,
+    // Remove comments, using the comment syntax of each file's extension
+    strip_comments: bool,
+    // Collapse runs of blank lines into one
+    squeeze_blank: bool,
+    // Keep only the first, last or a range of lines of each input
+    line_selection: Option<lines::LineSelection>,
+    // Mark where --head, --tail or --lines cut content
+    elide: bool,
+    // Keep or drop lines by regex, skipping files left without lines
+    line_filter: Option<lines::LineFilter>,
+    // Start every line of text output with this template, placeholders expanded
+    prefix_lines: Option<String>,
+    // Number the lines of text output, across all files or per file
+    number: Option<lines::Numbering>,
+    // Tokenizer used for --count-tokens and --max-tokens
+    tokenizer: tokens::Tokenizer,
+    // Inputs cut down to a number of tokens to fit the token budget
+    token_limits: std::collections::BTreeMap<PathBuf, usize>,
+}
+
 fn main() -> Result<()> {
     let start_time = std::time::Instant::now();
     
     let matches = Command::new("concatener")
         .version("0.1.0")
         .about("A fast command-line tool for concatenating multiple files")
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("split")
+                .about("Recreate the original files from an output written with --headers")
+                .arg(
+                    Arg::new("bundle")
+                        .help("Concatenated file to split")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("directory")
+                        .short('d')
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("Directory to restore the files into")
+                        .default_value(".")
+                )
+        )
+        .subcommand(
+            Command::new("locate")
+                .about("Find the input file and line behind a line of a concatenated output")
+                .arg(
+                    Arg::new("bundle")
+                        .help("Concatenated output")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("line")
+                        .long("line")
+                        .value_name("N")
+                        .help("Line of the output to locate (1-based)")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("FILE")
+                        .help("JSON index written with --index; without it the output must have been written with --headers")
+                )
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Re-check the inputs and outputs listed in a manifest written with --manifest")
+                .arg(
+                    Arg::new("manifest")
+                        .help("Manifest to verify")
+                        .required(true)
+                )
+        )
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
                 .value_name("FILE")
                 .help("Output file path")
-                .required(true)
+                .required_unless_present_any(["list", "dry_run"])
         )
         .arg(
             Arg::new("recursive")
@@ -26,6 +179,395 @@ fn main() -> Result<()> {
                 .help("Recursively search directories for files")
                 .action(clap::ArgAction::SetTrue)
         )
+        .arg(
+            Arg::new("decompress")
+                .short('z')
+                .long("decompress")
+                .help("Transparently decompress .gz, .bz2, .xz and .zst input files")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output format (text, markdown, json, jsonl, tar or zip); text, tar or zip are inferred from the output extension by default")
+                .value_parser(["text", "markdown", "json", "jsonl", "tar", "zip"])
+        )
+        .arg(
+            Arg::new("merge")
+                .long("merge")
+                .value_name("FORMAT")
+                .help("Merge the inputs into one valid document: json (arrays concatenated, objects merged), jsonl or yaml")
+                .value_parser(["json", "jsonl", "yaml"])
+        )
+        .arg(
+            Arg::new("merge_objects")
+                .long("merge-objects")
+                .value_name("STRATEGY")
+                .help("How --merge json combines top-level objects (deep or shallow)")
+                .value_parser(["deep", "shallow"])
+                .default_value("deep")
+        )
+        .arg(
+            Arg::new("csv")
+                .long("csv")
+                .help("Concatenate CSV/TSV files keeping only the first header row; delimiter and quoting are auto-detected")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("merge")
+        )
+        .arg(
+            Arg::new("csv_mismatch")
+                .long("csv-mismatch")
+                .value_name("POLICY")
+                .help("What --csv does when headers differ: error, or align columns by name")
+                .value_parser(["error", "align"])
+                .default_value("error")
+        )
+        .arg(
+            Arg::new("merge_by_timestamp")
+                .long("merge-by-timestamp")
+                .help("Interleave log lines from all inputs in time order; lines without a timestamp stay with the line before them")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["merge", "csv"])
+        )
+        .arg(
+            Arg::new("timestamp_format")
+                .long("timestamp-format")
+                .value_name("FORMAT")
+                .help("Timestamp format for --merge-by-timestamp: iso8601, syslog, epoch, or a strftime format such as \"%d/%b/%Y:%H:%M:%S %z\"")
+                .default_value("iso8601")
+        )
+        .arg(
+            Arg::new("timestamp_regex")
+                .long("timestamp-regex")
+                .value_name("REGEX")
+                .help("Find the timestamp with REGEX (first capture group, or the whole match) instead of at the start of the line")
+                .value_parser(lines::parse_regex)
+        )
+        .arg(
+            Arg::new("tree")
+                .long("tree")
+                .help("Start markdown output with a directory tree of the inputs")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("max_file_size")
+                .long("max-file-size")
+                .value_name("SIZE")
+                .help("List but omit the content of files larger than SIZE in markdown output (suffixes K, M, G)")
+                .value_parser(parts::parse_size)
+        )
+        .arg(
+            Arg::new("expand_includes")
+                .long("expand-includes")
+                .value_name("REGEX")
+                .help("Recursively inline files named by include lines matching REGEX; the first capture group is the path, relative to the including file")
+                .value_parser(lines::parse_regex)
+        )
+        .arg(
+            Arg::new("include_depth")
+                .long("include-depth")
+                .value_name("N")
+                .help("Maximum nesting depth of --expand-includes")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10")
+        )
+        .arg(
+            Arg::new("redact")
+                .long("redact")
+                .help("Replace secrets (AWS keys, private keys, JWTs, password= assignments, high-entropy strings) with [REDACTED]")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("redact_pattern")
+                .long("redact-pattern")
+                .value_name("REGEX")
+                .help("Also redact matches of REGEX (only its first capture group if it has one); implies --redact")
+                .value_parser(lines::parse_regex)
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("strip_comments")
+                .long("strip-comments")
+                .help("Remove comments from source files, based on their extension; string literals are left untouched")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("squeeze_blank")
+                .long("squeeze-blank")
+                .help("Collapse runs of blank lines into a single blank line")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("head")
+                .long("head")
+                .value_name("N")
+                .help("Keep only the first N lines of each file")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["tail", "lines"])
+        )
+        .arg(
+            Arg::new("tail")
+                .long("tail")
+                .value_name("N")
+                .help("Keep only the last N lines of each file")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with("lines")
+        )
+        .arg(
+            Arg::new("lines")
+                .long("lines")
+                .value_name("RANGE")
+                .help("Keep only lines START:END of each file (1-based, inclusive; START: and :END are open-ended)")
+                .value_parser(lines::parse_range)
+        )
+        .arg(
+            Arg::new("elide")
+                .long("elide")
+                .help("Insert a \"... (N lines omitted) ...\" marker where --head, --tail or --lines cut a file")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("grep")
+                .long("grep")
+                .value_name("REGEX")
+                .help("Keep only lines matching REGEX; files without a matching line are skipped")
+                .value_parser(lines::parse_regex)
+        )
+        .arg(
+            Arg::new("grep_v")
+                .long("grep-v")
+                .value_name("REGEX")
+                .help("Drop lines matching REGEX; files without a remaining line are skipped")
+                .value_parser(lines::parse_regex)
+        )
+        .arg(
+            Arg::new("after_context")
+                .short('A')
+                .long("after-context")
+                .value_name("N")
+                .help("Also keep N lines after each line kept by --grep or --grep-v")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("before_context")
+                .short('B')
+                .long("before-context")
+                .value_name("N")
+                .help("Also keep N lines before each line kept by --grep or --grep-v")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("context")
+                .short('C')
+                .long("context")
+                .value_name("N")
+                .help("Also keep N lines before and after each line kept by --grep or --grep-v")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("grep_prefix")
+                .long("grep-prefix")
+                .help("Start lines kept by --grep or --grep-v with \"path:lineno:\" (\"path-lineno-\" for context lines)")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("prefix_lines")
+                .long("prefix-lines")
+                .value_name("TEMPLATE")
+                .help("Start every line with TEMPLATE; placeholders: {path}, {relpath}, {name}, {index}, {lineno}")
+        )
+        .arg(
+            Arg::new("number")
+                .short('n')
+                .long("number")
+                .value_name("MODE")
+                .help("Number lines like cat -n, across all files (global, the default) or by line in each file (--number=file)")
+                .value_parser(["global", "file"])
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("global")
+        )
+        .arg(
+            Arg::new("count_tokens")
+                .long("count-tokens")
+                .help("Report per-file and total token counts")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("max_tokens")
+                .long("max-tokens")
+                .value_name("N")
+                .help("Drop or truncate inputs so their content stays under N tokens")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("token_policy")
+                .long("token-policy")
+                .value_name("POLICY")
+                .help("What to give up first when over --max-tokens")
+                .value_parser(["largest", "oldest", "last", "truncate"])
+                .default_value("largest")
+        )
+        .arg(
+            Arg::new("tokenizer")
+                .long("tokenizer")
+                .value_name("NAME")
+                .help("Tokenizer used to count tokens")
+                .value_parser(["o200k", "cl100k"])
+                .default_value("o200k")
+        )
+        .arg(
+            Arg::new("headers")
+                .short('H')
+                .long("headers")
+                .help("Write a header with the path and length before each file, so the output can be split again")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .value_name("FILE")
+                .help("Write a JSON index mapping output byte and line ranges to their inputs (a source map v3 if FILE ends in .map)")
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .value_name("FILE")
+                .help("Write a JSON manifest of each input's path, size, mtime, encoding and SHA-256, and of the output's hash")
+        )
+        .arg(
+            Arg::new("incremental")
+                .long("incremental")
+                .help("Skip writing when the inputs and options are unchanged since the last run")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("incremental_hash")
+                .long("incremental-hash")
+                .help("Like --incremental, but compare input and output contents by SHA-256 instead of modification times")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("exit_code")
+                .long("exit-code")
+                .help("With --incremental, exit with code 3 when the output was already up to date")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("FILE")
+                .help("Write a JSON report of the run (inputs, files, skipped files, durations, output hashes) to FILE, or to stdout with -")
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Print the files the inputs resolve to, in order, with their sizes and encodings, and write nothing")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("list")
+        )
+        .arg(
+            Arg::new("list")
+                .long("list")
+                .help("Print the paths the inputs resolve to, one per line, and write nothing")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("print0")
+                .long("print0")
+                .help("With --list, end each path with a NUL byte instead of a newline")
+                .action(clap::ArgAction::SetTrue)
+                .requires("list")
+        )
+        .arg(
+            Arg::new("keep_going")
+                .long("keep-going")
+                .help("Skip unreadable files and directories, summarize them and exit with code 2")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("append")
+                .long("append")
+                .help("Add to the end of an existing output instead of replacing it")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("follow")
+                .long("follow")
+                .help("Keep running and append the lines added to the inputs, following rotated logs")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Keep running and regenerate the output whenever the inputs change")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("debounce")
+                .long("debounce")
+                .value_name("MS")
+                .help("With --watch, wait until changes have been quiet for MS milliseconds before rebuilding")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("300")
+        )
+        .arg(
+            Arg::new("split_size")
+                .long("split-size")
+                .value_name("SIZE")
+                .help("Start a new numbered output part after SIZE bytes (suffixes K, M, G)")
+                .value_parser(parts::parse_size)
+        )
+        .arg(
+            Arg::new("split_lines")
+                .long("split-lines")
+                .value_name("N")
+                .help("Start a new numbered output part after N lines")
+                .value_parser(clap::value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("split_files")
+                .long("split-files")
+                .value_name("N")
+                .help("Start a new numbered output part after N input files")
+                .value_parser(clap::value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("split_whole_files")
+                .long("split-whole-files")
+                .help("Never split inside an input file when splitting by size or lines")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("split_manifest")
+                .long("split-manifest")
+                .value_name("FILE")
+                .help("Write the list of inputs contained in each output part to FILE")
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .value_name("FORMAT")
+                .help("Compress the output (gzip, bzip2, xz, zstd or none); inferred from the output extension by default")
+                .value_parser(["gzip", "bzip2", "xz", "zstd", "none"])
+        )
+        .arg(
+            Arg::new("compression_level")
+                .long("compression-level")
+                .value_name("LEVEL")
+                .help("Compression level (gzip/xz: 0-9, bzip2: 1-9, zstd: 1-22)")
+                .value_parser(clap::value_parser!(i32))
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Number of worker threads for zstd compression")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("0")
+        )
         .arg(
             Arg::new("inputs")
                 .help("Input files, directories, or patterns to concatenate")
@@ -34,12 +576,540 @@ fn main() -> Result<()> {
         )
         .get_matches();
 
-    let output_path = matches.get_one::<String>("output").unwrap();
-    let inputs: Vec<&String> = matches.get_many::<String>("inputs").unwrap().collect();
-    let recursive = matches.get_flag("recursive");
+    match matches.subcommand() {
+        Some(("split", split_matches)) => return run_split(split_matches, start_time),
+        Some(("locate", locate_matches)) => return run_locate(locate_matches),
+        Some(("verify", verify_matches)) => return run_verify(verify_matches, start_time),
+        _ => {}
+    }
+
+    if matches.get_flag("list") || matches.get_flag("dry_run") {
+        return run_list(&matches);
+    }
+
+    let output_path = matches.get_one::<String>("output").unwrap();
+    let inputs: Vec<&String> = matches.get_many::<String>("inputs").unwrap().collect();
+    let recursive = matches.get_flag("recursive");
+    if matches.get_one::<String>("report").is_some_and(|destination| destination == "-") {
+        STATUS_TO_STDERR.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    // Use the explicit --compress format, or infer it from the output extension
+    let compression_format = match matches.get_one::<String>("compress") {
+        Some(name) => output::parse_compression(name)?,
+        None => archive::compression_for(Path::new(output_path)),
+    };
+    let compression_level = matches.get_one::<i32>("compression_level").copied();
+    let compression = match compression_format {
+        Some(format) => Some(CompressionSettings::new(format, compression_level, *matches.get_one::<u32>("threads").unwrap())?),
+        None if compression_level.is_some() => {
+            return Err(anyhow::anyhow!("--compression-level requires --compress or a compressed output extension"));
+        }
+        None => None,
+    };
+
+    let format = match matches.get_one::<String>("format") {
+        Some(name) => OutputFormat::parse(name)?,
+        None => OutputFormat::infer(Path::new(output_path)),
+    };
+    if format == OutputFormat::Zip && compression.is_some() {
+        return Err(anyhow::anyhow!("Zip output is already compressed; --compress cannot be combined with --format zip"));
+    }
+
+    let headers = matches.get_flag("headers");
+    if headers && format != OutputFormat::Text {
+        return Err(anyhow::anyhow!("--headers only applies to text output"));
+    }
+
+    let tree = matches.get_flag("tree");
+    let max_file_size = matches.get_one::<u64>("max_file_size").copied();
+    if (tree || max_file_size.is_some()) && format != OutputFormat::Markdown {
+        return Err(anyhow::anyhow!("--tree and --max-file-size only apply to markdown output"));
+    }
+
+    let merge = match matches.get_one::<String>("merge") {
+        Some(name) => {
+            if format != OutputFormat::Text || headers {
+                return Err(anyhow::anyhow!("--merge only applies to text output without --headers"));
+            }
+            Some(merge::MergeSettings {
+                format: merge::MergeFormat::parse(name)?,
+                objects: merge::ObjectMerge::parse(matches.get_one::<String>("merge_objects").unwrap())?,
+            })
+        }
+        None => None,
+    };
+
+    let csv = if matches.get_flag("csv") {
+        if format != OutputFormat::Text || headers {
+            return Err(anyhow::anyhow!("--csv only applies to text output without --headers"));
+        }
+        Some(table::HeaderMismatch::parse(matches.get_one::<String>("csv_mismatch").unwrap())?)
+    } else {
+        None
+    };
+
+    let merge_by_timestamp = if matches.get_flag("merge_by_timestamp") {
+        if format != OutputFormat::Text || headers {
+            return Err(anyhow::anyhow!("--merge-by-timestamp only applies to text output without --headers"));
+        }
+        Some(logmerge::TimestampSettings {
+            format: logmerge::TimestampFormat::parse(matches.get_one::<String>("timestamp_format").unwrap()),
+            regex: matches.get_one::<regex::Regex>("timestamp_regex").cloned(),
+        })
+    } else {
+        None
+    };
+
+    let index = matches.get_one::<String>("index").map(PathBuf::from);
+    if index.is_some() && (format != OutputFormat::Text || merge.is_some() || csv.is_some() || merge_by_timestamp.is_some()) {
+        return Err(anyhow::anyhow!("--index only applies to plain text output"));
+    }
+
+    let split_settings = parts::SplitSettings {
+        max_bytes: matches.get_one::<u64>("split_size").copied(),
+        max_lines: matches.get_one::<u64>("split_lines").copied(),
+        max_files: matches.get_one::<u64>("split_files").map(|&count| count as usize),
+        whole_files: matches.get_flag("split_whole_files"),
+        manifest: matches.get_one::<String>("split_manifest").map(PathBuf::from),
+    };
+    let split = if split_settings.max_bytes.is_some() || split_settings.max_lines.is_some() || split_settings.max_files.is_some() {
+        if format != OutputFormat::Text || merge.is_some() || csv.is_some() || merge_by_timestamp.is_some() || index.is_some() {
+            return Err(anyhow::anyhow!("Splitting only applies to text output without --merge, --csv, --merge-by-timestamp or --index"));
+        }
+        Some(split_settings)
+    } else if split_settings != parts::SplitSettings::default() {
+        return Err(anyhow::anyhow!("--split-whole-files and --split-manifest require --split-size, --split-lines or --split-files"));
+    } else {
+        None
+    };
+
+    let watch = matches.get_flag("watch");
+    if watch && (split.is_some() || matches.get_flag("incremental") || matches.get_flag("incremental_hash")) {
+        return Err(anyhow::anyhow!("--watch cannot be combined with splitting or --incremental"));
+    }
+    if matches.get_flag("exit_code") && !matches.get_flag("incremental") && !matches.get_flag("incremental_hash") {
+        return Err(anyhow::anyhow!("--exit-code only applies with --incremental or --incremental-hash"));
+    }
+
+    let append = matches.get_flag("append");
+    let follow = matches.get_flag("follow");
+    if (append || follow)
+        && (!matches!(format, OutputFormat::Text | OutputFormat::Markdown)
+            || merge.is_some() || csv.is_some() || merge_by_timestamp.is_some() || split.is_some() || index.is_some()
+            || matches.get_flag("incremental") || matches.get_flag("incremental_hash"))
+    {
+        return Err(anyhow::anyhow!(
+            "--append and --follow only apply to text and markdown output without --merge, --csv, --merge-by-timestamp, splitting, --index or --incremental"
+        ));
+    }
+    if follow && (watch || compression.is_some()) {
+        return Err(anyhow::anyhow!("--follow cannot be combined with --watch or compressed output"));
+    }
+    // Every rebuild would append all the inputs again
+    if append && watch {
+        return Err(anyhow::anyhow!("--append cannot be combined with --watch"));
+    }
+
+    let line_selection = if let Some(&count) = matches.get_one::<usize>("head") {
+        Some(lines::LineSelection::Head(count))
+    } else if let Some(&count) = matches.get_one::<usize>("tail") {
+        Some(lines::LineSelection::Tail(count))
+    } else {
+        matches.get_one::<lines::LineSelection>("lines").copied()
+    };
+    let elide = matches.get_flag("elide");
+    if elide && line_selection.is_none() {
+        return Err(anyhow::anyhow!("--elide requires --head, --tail or --lines"));
+    }
+
+    let context = matches.get_one::<usize>("context").copied();
+    let line_filter = lines::LineFilter {
+        grep: matches.get_one::<regex::Regex>("grep").cloned(),
+        grep_v: matches.get_one::<regex::Regex>("grep_v").cloned(),
+        before: matches.get_one::<usize>("before_context").copied().or(context).unwrap_or(0),
+        after: matches.get_one::<usize>("after_context").copied().or(context).unwrap_or(0),
+        prefix: matches.get_flag("grep_prefix"),
+        unmatched: Default::default(),
+    };
+    let line_filter = if line_filter.grep.is_some() || line_filter.grep_v.is_some() {
+        Some(line_filter)
+    } else if line_filter.before > 0 || line_filter.after > 0 || line_filter.prefix {
+        return Err(anyhow::anyhow!("-A, -B, -C and --grep-prefix require --grep or --grep-v"));
+    } else {
+        None
+    };
+
+    let prefix_lines = matches.get_one::<String>("prefix_lines").cloned();
+    let number = matches.get_one::<String>("number").map(|name| lines::Numbering::parse(name)).transpose()?;
+    if (prefix_lines.is_some() || number.is_some()) && (format != OutputFormat::Text || merge.is_some() || csv.is_some()) {
+        return Err(anyhow::anyhow!("--prefix-lines and --number only apply to plain text output"));
+    }
+    // The index maps each output line to the same line of its input, which
+    // only holds while no line is added, removed or moved
+    if index.is_some()
+        && (line_selection.is_some() || line_filter.is_some() || matches.get_flag("strip_comments") || matches.get_flag("squeeze_blank")
+            || matches.get_one::<regex::Regex>("expand_includes").is_some() || prefix_lines.is_some() || number.is_some())
+    {
+        return Err(anyhow::anyhow!(
+            "--index cannot be combined with --head, --tail, --lines, --grep, --grep-v, --strip-comments, --squeeze-blank, --expand-includes, --prefix-lines or --number"
+        ));
+    }
+    // Followed lines arrive a few at a time, so only redaction and plain
+    // --grep/--grep-v can be applied to them
+    if follow
+        && (line_selection.is_some() || matches.get_flag("strip_comments") || matches.get_flag("squeeze_blank")
+            || matches.get_one::<regex::Regex>("expand_includes").is_some() || matches.get_one::<usize>("max_tokens").is_some()
+            || prefix_lines.is_some() || number.is_some()
+            || line_filter.as_ref().is_some_and(|filter| filter.before > 0 || filter.after > 0 || filter.prefix))
+    {
+        return Err(anyhow::anyhow!(
+            "--follow cannot be combined with --head, --tail, --lines, --strip-comments, --squeeze-blank, --expand-includes, --max-tokens, --prefix-lines, --number, grep context or --grep-prefix"
+        ));
+    }
+
+    let includes = match matches.get_one::<regex::Regex>("expand_includes") {
+        Some(pattern) if pattern.captures_len() < 2 => {
+            return Err(anyhow::anyhow!("--expand-includes needs a capture group for the included path"));
+        }
+        Some(pattern) => Some(includes::IncludeExpander::new(
+            pattern.clone(),
+            *matches.get_one::<usize>("include_depth").unwrap(),
+            matches.get_flag("decompress"),
+        )),
+        None => None,
+    };
+
+    let redact_patterns: Vec<regex::Regex> = matches.get_many::<regex::Regex>("redact_pattern").unwrap_or_default().cloned().collect();
+    let redactor = if matches.get_flag("redact") || !redact_patterns.is_empty() {
+        Some(redact::Redactor::new(redact_patterns))
+    } else {
+        None
+    };
+
+    let options = ConcatOptions {
+        decompress: matches.get_flag("decompress"),
+        report: matches.contains_id("report").then(report::RunRecorder::default),
+        manifest: matches.contains_id("manifest").then(manifest::InputRecorder::default),
+        keep_going: matches.get_flag("keep_going").then(failures::Failures::default),
+        compression,
+        format,
+        merge,
+        csv,
+        merge_by_timestamp,
+        headers,
+        index,
+        append,
+        atomic: watch,
+        split,
+        tree,
+        max_file_size,
+        includes,
+        redactor,
+        strip_comments: matches.get_flag("strip_comments"),
+        squeeze_blank: matches.get_flag("squeeze_blank"),
+        line_selection,
+        elide,
+        line_filter,
+        prefix_lines,
+        number,
+        tokenizer: tokens::Tokenizer::parse(matches.get_one::<String>("tokenizer").unwrap())?,
+        token_limits: Default::default(),
+    };
+    let token_policy = tokens::TokenPolicy::parse(matches.get_one::<String>("token_policy").unwrap())?;
+
+    let written_files = run_concatenation(&matches, &options, token_policy, start_time)?;
+    if !follow && !watch && options.keep_going.as_ref().is_some_and(|failures| !failures.list().is_empty()) {
+        std::io::Write::flush(&mut std::io::stdout())?;
+        std::process::exit(failures::EXIT_PARTIAL);
+    }
+    if follow && !written_files.is_empty() {
+        follow::follow(&written_files, output_path, &options)?;
+    }
+
+    if watch {
+        let mut roots = std::collections::BTreeMap::new();
+        for input in &inputs {
+            let (root, recursive) = watch::watch_root(&expand_home(input), recursive);
+            *roots.entry(root).or_insert(false) |= recursive;
+        }
+        let mut ignored = vec![PathBuf::from(output_path), PathBuf::from(format!("{}.tmp", output_path))];
+        // Every sidecar the run writes, so that a pattern matching one of them
+        // does not rebuild on every rebuild
+        ignored.extend(options.index.iter().cloned());
+        ignored.extend(matches.get_one::<String>("manifest").map(PathBuf::from));
+        ignored.extend(matches.get_one::<String>("report").filter(|destination| *destination != "-").map(PathBuf::from));
+        ignored.push(fingerprint::fingerprint_path(output_path));
+
+        // Failures are reported by the rebuilds, not by change detection
+        let scratch = options.keep_going.as_ref().map(|_| failures::Failures::default());
+        let resolve = || -> Result<Vec<PathBuf>> {
+            let mut files = Vec::new();
+            for input in &inputs {
+                let resolved = resolve_input_files(input, recursive, scratch.as_ref());
+                files.extend(failures::skip_on_error(resolved, Path::new(input.as_str()), scratch.as_ref())?);
+            }
+            files.sort();
+            Ok(files)
+        };
+        let debounce = std::time::Duration::from_millis(*matches.get_one::<u64>("debounce").unwrap());
+        watch::watch(&roots, &ignored, debounce, resolve, || {
+            run_concatenation(&matches, &options, token_policy, std::time::Instant::now()).map(|_| ())
+        })?;
+    }
+    Ok(())
+}
+
+// Resolve the inputs, write the output and print the reports, returning the
+// files that were written
+fn run_concatenation(
+    matches: &clap::ArgMatches,
+    options: &ConcatOptions,
+    token_policy: tokens::TokenPolicy,
+    start_time: std::time::Instant,
+) -> Result<Vec<PathBuf>> {
+    let output_path = matches.get_one::<String>("output").unwrap();
+    let inputs: Vec<&String> = matches.get_many::<String>("inputs").unwrap().collect();
+    let recursive = matches.get_flag("recursive");
+    let count_tokens = matches.get_flag("count_tokens");
+    let max_tokens = matches.get_one::<usize>("max_tokens").copied();
+    let mut options = options.clone();
+
+    let failures = options.keep_going.as_ref();
+    if let Some(failures) = failures {
+        failures.clear();
+    }
+    let resolution_start = std::time::Instant::now();
+    let mut all_files = resolve_all_inputs(&inputs, recursive, failures, true)?;
+    status!("Found {} files to process", all_files.len());
+    
+    // Force flush all output
+    std::io::Write::flush(&mut std::io::stdout())?;
+
+    // Filled in as the run goes, written at the end with --report
+    let mut run_report = options.report.as_ref().map(|_| report::RunReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        inputs: inputs.iter().map(|input| input.to_string()).collect(),
+        resolved: all_files.iter().map(|file_path| relative_path(file_path)).collect(),
+        updated: true,
+        files: Vec::new(),
+        skipped: Vec::new(),
+        durations: report::Durations { resolution_ms: report::milliseconds(resolution_start.elapsed()), ..Default::default() },
+        outputs: Vec::new(),
+    });
+
+    if all_files.is_empty() {
+        eprintln!("Warning: No input files found to concatenate");
+        if let Some(failures) = failures {
+            failures::print_failure_summary(&failures.list());
+        }
+        finish_run_report(matches, run_report, &options, start_time)?;
+        return Ok(all_files);
+    }
+    
+    // Compare the resolved inputs with the fingerprint of the previous run
+    let incremental_hash = matches.get_flag("incremental_hash");
+    let incremental = if matches.get_flag("incremental") || incremental_hash {
+        let arguments: Vec<String> = std::env::args().skip(1).collect();
+        let inputs = fingerprint::inputs_fingerprint(&all_files, incremental_hash, options.decompress)?;
+        let fingerprint_path = fingerprint::fingerprint_path(output_path);
+        if fingerprint::read_fingerprint(&fingerprint_path).is_some_and(|previous| previous.is_current(&arguments, &inputs)) {
+            status!("Up to date: {} ({} inputs unchanged)", output_path, all_files.len());
+            print_processing_time(start_time.elapsed());
+            if let Some(run_report) = &mut run_report {
+                run_report.updated = false;
+                run_report.outputs = vec![manifest::output_record(Path::new(output_path))?];
+            }
+            finish_run_report(matches, run_report, &options, start_time)?;
+            if matches.get_flag("exit_code") {
+                std::io::Write::flush(&mut std::io::stdout())?;
+                std::process::exit(fingerprint::EXIT_UP_TO_DATE);
+            }
+            return Ok(all_files);
+        }
+        Some((fingerprint_path, arguments, inputs))
+    } else {
+        None
+    };
+
+    // Fit the inputs into the token budget before writing anything
+    let token_plan = if count_tokens || max_tokens.is_some() {
+        let plan = tokens::plan_budget(&all_files, &options, max_tokens, token_policy)?;
+        if !plan.dropped.is_empty() {
+            eprintln!("Warning: Dropped {} files to stay within {} tokens", plan.dropped.len(), max_tokens.unwrap_or_default());
+        }
+        if let Some(run_report) = &mut run_report {
+            run_report.skipped.extend(plan.dropped.iter().map(|file_path| report::SkippedFile {
+                path: relative_path(file_path),
+                reason: format!("Dropped to stay within {} tokens", max_tokens.unwrap_or_default()),
+            }));
+        }
+        all_files = plan.kept.clone();
+        options.token_limits = plan.truncated.clone();
+        Some(plan)
+    } else {
+        None
+    };
+    
+    // The --max-tokens pass above reads the inputs too; only the reads made
+    // while writing count as reading
+    if let Some(recorder) = &options.report {
+        recorder.reset_reading();
+    }
+    let writing_start = std::time::Instant::now();
+    let outputs = concatenate_files(&all_files, output_path, &options)
+        .with_context(|| format!("Failed to concatenate files to: {}", output_path))?;
+    // Inputs that failed to read under --keep-going are not part of the output
+    if let Some(failures) = failures {
+        all_files.retain(|file_path| !failures.contains(file_path));
+    }
+    // Nor are files left without any line by --grep or --grep-v
+    if let Some(filter) = &options.line_filter {
+        let unmatched = filter.unmatched();
+        if let Some(run_report) = &mut run_report {
+            run_report.skipped.extend(all_files.iter().filter(|file_path| unmatched.contains(*file_path)).map(|file_path| report::SkippedFile {
+                path: relative_path(file_path),
+                reason: "No lines matched --grep or --grep-v".to_string(),
+            }));
+        }
+        all_files.retain(|file_path| !unmatched.contains(file_path));
+    }
+    if let (Some(run_report), Some(recorder)) = (&mut run_report, &options.report) {
+        run_report.durations.writing_ms = report::milliseconds(writing_start.elapsed().saturating_sub(recorder.reading_time()));
+        run_report.files = recorder.files(&all_files);
+        run_report.outputs = outputs.iter().map(|output| manifest::output_record(output)).collect::<Result<_>>()?;
+    }
+
+    if let [single_output] = outputs.as_slice() {
+        status!("Successfully concatenated {} files to: {}", all_files.len(), single_output.display());
+    } else {
+        status!("Successfully concatenated {} files into {} parts:", all_files.len(), outputs.len());
+        for part in &outputs {
+            status!("  {}", part.display());
+        }
+    }
+    if let (Some(manifest_path), Some(recorded)) = (matches.get_one::<String>("manifest"), &options.manifest) {
+        let manifest = manifest::build_manifest(&all_files, &outputs, options.decompress, recorded)?;
+        manifest::write_manifest(Path::new(manifest_path), &manifest)?;
+        status!("Manifest written to: {}", manifest_path);
+    }
+    // A partial output must not pass for up to date on the next run
+    if let Some((fingerprint_path, arguments, inputs)) = incremental
+        && failures.is_none_or(|failures| failures.list().is_empty())
+    {
+        // Sidecar files count as outputs too, so deleting one forces a rebuild
+        let mut written = outputs.clone();
+        written.extend(options.index.iter().cloned());
+        written.extend(matches.get_one::<String>("manifest").map(PathBuf::from));
+        let fingerprint = fingerprint::Fingerprint {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            arguments,
+            inputs,
+            outputs: fingerprint::outputs_fingerprint(&written, incremental_hash)?,
+        };
+        fingerprint::write_fingerprint(&fingerprint_path, &fingerprint)?;
+    }
+    if count_tokens && let Some(plan) = &token_plan {
+        tokens::print_token_report(plan, options.tokenizer);
+    }
+    if let Some(includes) = &options.includes {
+        let mut dependencies = includes.dependencies();
+        dependencies.retain(|file_path, _| all_files.contains(file_path));
+        let mut warnings = includes.warnings();
+        warnings.retain(|file_path, _| all_files.contains(file_path));
+        includes::print_warnings(&warnings);
+        includes::print_dependencies(&dependencies);
+    }
+    if let Some(redactor) = &options.redactor {
+        // Inputs dropped by the token budget were read but not written
+        let mut report = redactor.report();
+        report.retain(|file_path, _| all_files.contains(file_path));
+        redact::print_redaction_report(&report);
+    }
+    if let Some(failures) = failures {
+        let failures = failures.list();
+        if !failures.is_empty() {
+            failures::print_failure_summary(&failures);
+        }
+    }
+    print_processing_time(start_time.elapsed());
+    finish_run_report(matches, run_report, &options, start_time)?;
+    
+    // Ensure all output is flushed before exiting
+    std::io::Write::flush(&mut std::io::stdout())?;
+    Ok(all_files)
+}
+
+fn run_split(matches: &clap::ArgMatches, start_time: std::time::Instant) -> Result<()> {
+    let bundle_path = matches.get_one::<String>("bundle").unwrap();
+    let directory = matches.get_one::<String>("directory").unwrap();
+
+    let files = bundle::split_bundle(Path::new(bundle_path), Path::new(directory))
+        .with_context(|| format!("Failed to split: {}", bundle_path))?;
+
+    println!("Successfully restored {} files to: {}", files.len(), directory);
+    print_processing_time(start_time.elapsed());
+    std::io::Write::flush(&mut std::io::stdout())?;
+    Ok(())
+}
+
+// Check the inputs and outputs recorded in a manifest against the files on disk
+fn run_verify(matches: &clap::ArgMatches, start_time: std::time::Instant) -> Result<()> {
+    let manifest_path = matches.get_one::<String>("manifest").unwrap();
+    let manifest = manifest::read_manifest(Path::new(manifest_path))?;
+
+    let drift = manifest::verify_manifest(&manifest);
+    for message in &drift {
+        println!("DRIFT {}", message);
+    }
+    print_processing_time(start_time.elapsed());
+    if !drift.is_empty() {
+        return Err(anyhow::anyhow!("{} of {} files drifted from {}", drift.len(), manifest.inputs.len() + manifest.outputs.len(), manifest_path));
+    }
+    println!("Verified {} inputs and {} outputs against: {}", manifest.inputs.len(), manifest.outputs.len(), manifest_path);
+    Ok(())
+}
+
+// Map a line of a concatenated output back to its input and source line
+fn run_locate(matches: &clap::ArgMatches) -> Result<()> {
+    let bundle_path = matches.get_one::<String>("bundle").unwrap();
+    let line = *matches.get_one::<u64>("line").unwrap();
+
+    let index = match matches.get_one::<String>("index") {
+        Some(index_path) => index::read_index(Path::new(index_path))?,
+        None => {
+            let bytes = archive::read_input(Path::new(bundle_path), true)?;
+            index::index_from_bundle(bundle_path, &bytes)
+                .with_context(|| format!("{} has no headers; pass the index written with --index", bundle_path))?
+        }
+    };
+
+    match index.locate(line) {
+        Some((path, source_line)) => println!("{}:{}: {}:{}", bundle_path, line, path, source_line),
+        None => return Err(anyhow::anyhow!("Line {} of {} does not belong to any input", line, bundle_path)),
+    }
+    Ok(())
+}
+
+// Complete the report with the failures and timings and write it
+fn finish_run_report(matches: &clap::ArgMatches, run_report: Option<report::RunReport>, options: &ConcatOptions, start_time: std::time::Instant) -> Result<()> {
+    let (Some(mut run_report), Some(destination)) = (run_report, matches.get_one::<String>("report")) else {
+        return Ok(());
+    };
+    if let Some(failures) = &options.keep_going {
+        run_report.skipped.extend(failures.list().into_iter().map(|(path, reason)| report::SkippedFile { path, reason }));
+    }
+    if let Some(recorder) = &options.report {
+        run_report.durations.reading_ms = report::milliseconds(recorder.reading_time());
+    }
+    run_report.durations.total_ms = report::milliseconds(start_time.elapsed());
+    report::write_report(destination, &run_report)
+}
 
+// Resolve every input into the sorted list of files to concatenate
+fn resolve_all_inputs(inputs: &[&String], recursive: bool, failures: Option<&failures::Failures>, show_progress: bool) -> Result<Vec<PathBuf>> {
     // Show loading indicator while resolving files
-    let loading = ProgressBar::new_spinner();
+    let loading = if show_progress { ProgressBar::new_spinner() } else { ProgressBar::hidden() };
     loading.set_style(
         ProgressStyle::default_spinner()
             .template("{spinner:.green} {msg}")
@@ -51,7 +1121,7 @@ fn main() -> Result<()> {
     let mut all_files = Vec::new();
     
     for input in inputs {
-        let files = resolve_input_files(input, recursive)
+        let files = failures::skip_on_error(resolve_input_files(input, recursive, failures), Path::new(input.as_str()), failures)
             .with_context(|| format!("Failed to resolve input: {}", input))?;
         all_files.extend(files);
         
@@ -61,44 +1131,79 @@ fn main() -> Result<()> {
     
     // Finish loading indicator and ensure it's properly cleaned up
     loading.finish_and_clear();
-    println!("Found {} files to process", all_files.len());
-    
-    // Force flush all output
-    std::io::Write::flush(&mut std::io::stdout())?;
-
-    if all_files.is_empty() {
-        eprintln!("Warning: No input files found to concatenate");
-        return Ok(());
-    }
 
     // Sort files for consistent ordering
     all_files.sort();
-    
-    concatenate_files(&all_files, output_path)
-        .with_context(|| format!("Failed to concatenate files to: {}", output_path))?;
 
-    let duration = start_time.elapsed();
-    println!("Successfully concatenated {} files to: {}", all_files.len(), output_path);
-    
-    // Display processing time in a human-readable format
+    if let Some(failures) = failures {
+        all_files.retain(|file_path| match failures::check_readable(file_path) {
+            Ok(()) => true,
+            Err(error) => {
+                failures.record(file_path, &error);
+                false
+            }
+        });
+    }
+    Ok(all_files)
+}
+
+// --dry-run and --list: show what the inputs resolve to without writing anything
+fn run_list(matches: &clap::ArgMatches) -> Result<()> {
+    let inputs: Vec<&String> = matches.get_many::<String>("inputs").unwrap().collect();
+    let failures = matches.get_flag("keep_going").then(failures::Failures::default);
+    let files = resolve_all_inputs(&inputs, matches.get_flag("recursive"), failures.as_ref(), false)?;
+    let failures = failures.map(|failures| failures.list()).unwrap_or_default();
+
+    if matches.get_flag("list") {
+        // Raw path bytes, so any file name survives a pipeline
+        let terminator: &[u8] = if matches.get_flag("print0") { b"\0" } else { b"\n" };
+        let mut stdout = std::io::stdout().lock();
+        for file_path in &files {
+            stdout.write_all(file_path.as_os_str().as_encoded_bytes())?;
+            stdout.write_all(terminator)?;
+        }
+        stdout.flush()?;
+        for (path, reason) in &failures {
+            eprintln!("Warning: Skipped {}: {}", path, reason);
+        }
+    } else {
+        let decompress = matches.get_flag("decompress");
+        let mut total = 0;
+        for file_path in &files {
+            let bytes = archive::read_input(file_path, decompress)?;
+            let encoding = if is_binary(&bytes) { "binary" } else { decode_with_encoding_detection(&bytes).1 };
+            println!("{:>12}  {:<12}  {}", bytes.len(), encoding, relative_path(file_path));
+            total += bytes.len();
+        }
+        println!("{} files, {} bytes (dry run, nothing written)", files.len(), total);
+        if !failures.is_empty() {
+            failures::print_failure_summary(&failures);
+        }
+        std::io::stdout().flush()?;
+    }
+
+    if !failures.is_empty() {
+        std::process::exit(failures::EXIT_PARTIAL);
+    }
+    Ok(())
+}
+
+// Display processing time in a human-readable format
+fn print_processing_time(duration: std::time::Duration) {
     if duration.as_millis() < 1000 {
-        println!("Processing time: {} ms", duration.as_millis());
+        status!("Processing time: {} ms", duration.as_millis());
     } else if duration.as_secs() < 60 {
-        println!("Processing time: {:.2} s", duration.as_secs_f64());
+        status!("Processing time: {:.2} s", duration.as_secs_f64());
     } else {
         let minutes = duration.as_secs() / 60;
         let seconds = duration.as_secs() % 60;
-        println!("Processing time: {}m {}s", minutes, seconds);
+        status!("Processing time: {}m {}s", minutes, seconds);
     }
-    
-    // Ensure all output is flushed before exiting
-    std::io::Write::flush(&mut std::io::stdout())?;
-    Ok(())
 }
 
-fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
-    // Expand ~ to home directory
-    let expanded_input = if input.starts_with("~/") {
+// Expand ~ to home directory
+fn expand_home(input: &str) -> String {
+    if input.starts_with("~/") {
         if let Some(home_dir) = std::env::var_os("HOME") {
             input.replacen("~", &home_dir.to_string_lossy(), 1)
         } else {
@@ -106,8 +1211,31 @@ fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
         }
     } else {
         input.to_string()
-    };
+    }
+}
+
+// Archives met as files, named directly or found in a directory, are read as
+// the virtual directories they hold, as if given as "archive//"
+fn resolve_input_files(input: &str, recursive: bool, failures: Option<&failures::Failures>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for file_path in resolve_paths(input, recursive, failures)? {
+        if archive::archive_kind(&file_path).is_some() && file_path.is_file() {
+            files.extend(failures::skip_on_error(archive::resolve_members(&file_path, ""), &file_path, failures)?);
+        } else {
+            files.push(file_path);
+        }
+    }
+    Ok(files)
+}
+
+fn resolve_paths(input: &str, recursive: bool, failures: Option<&failures::Failures>) -> Result<Vec<PathBuf>> {
+    let expanded_input = expand_home(input);
     
+    // Archives act as virtual directories: "logs.tar.gz//**/*.log"
+    if let Some((archive_path, member_pattern)) = archive::split_member_path(&expanded_input) {
+        return archive::resolve_members(&archive_path, &member_pattern);
+    }
+
     let path = Path::new(&expanded_input);
     
     // Check if it's a directory with wildcard pattern (like "dir/*.json")
@@ -122,7 +1250,7 @@ fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
                 // It's a directory with wildcard pattern
                 if recursive {
                     let mut files = Vec::new();
-                    collect_files_recursive_with_pattern(dir, pattern, &mut files)?;
+                    collect_files_recursive_with_pattern(dir, pattern, &mut files, failures)?;
                     Ok(files)
                 } else {
                     collect_files_in_directory_with_pattern(dir, pattern, &mut vec![])
@@ -130,7 +1258,7 @@ fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
             } else {
                 // Not a valid directory, treat as regular wildcard
                 if recursive {
-                    collect_files_with_wildcard_recursive(&expanded_input)
+                    collect_files_with_wildcard_recursive(&expanded_input, failures)
                 } else {
                     collect_files_with_wildcard(&expanded_input)
                 }
@@ -138,7 +1266,7 @@ fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
         } else {
             // No directory path, treat as regular wildcard
             if recursive {
-                collect_files_with_wildcard_recursive(&expanded_input)
+                collect_files_with_wildcard_recursive(&expanded_input, failures)
             } else {
                 collect_files_with_wildcard(&expanded_input)
             }
@@ -146,14 +1274,14 @@ fn resolve_input_files(input: &str, recursive: bool) -> Result<Vec<PathBuf>> {
     } else if path.is_dir() {
         // Handle directory - get all files in directory
         if recursive {
-            collect_files_recursive(path)
+            collect_files_recursive(path, failures)
         } else {
             collect_files_in_directory(path)
         }
     } else if expanded_input.contains('*') {
         // Handle wildcard pattern (without directory path)
         if recursive {
-            collect_files_with_wildcard_recursive(&expanded_input)
+            collect_files_with_wildcard_recursive(&expanded_input, failures)
         } else {
             collect_files_with_wildcard(&expanded_input)
         }
@@ -189,12 +1317,11 @@ fn collect_files_in_directory_with_pattern(dir: &Path, pattern: &str, _files: &m
         
         if entry_path.is_file() {
             // Check if filename matches the pattern
-            if let Some(file_name) = entry_path.file_name() {
-                if let Some(file_name_str) = file_name.to_str() {
-                    if matches_pattern(file_name_str, pattern) {
-                        result.push(entry_path);
-                    }
-                }
+            if let Some(file_name) = entry_path.file_name()
+                && let Some(file_name_str) = file_name.to_str()
+                && matches_pattern(file_name_str, pattern)
+            {
+                result.push(entry_path);
             }
         }
     }
@@ -212,7 +1339,7 @@ fn collect_files_with_wildcard(pattern: &str) -> Result<Vec<PathBuf>> {
         
         let mut result = Vec::new();
         for path in paths {
-            let path = path.with_context(|| format!("Error reading file path"))?;
+            let path = path.context("Error reading file path")?;
             if path.is_file() {
                 result.push(path);
             }
@@ -227,14 +1354,12 @@ fn collect_files_with_wildcard(pattern: &str) -> Result<Vec<PathBuf>> {
             let entry = entry?;
             let path = entry.path();
             
-            if path.is_file() {
-                if let Some(file_name) = path.file_name() {
-                    if let Some(file_name_str) = file_name.to_str() {
-                        if matches_pattern(file_name_str, pattern) {
-                            result.push(path);
-                        }
-                    }
-                }
+            if path.is_file()
+                && let Some(file_name) = path.file_name()
+                && let Some(file_name_str) = file_name.to_str()
+                && matches_pattern(file_name_str, pattern)
+            {
+                result.push(path);
             }
         }
         result
@@ -243,7 +1368,7 @@ fn collect_files_with_wildcard(pattern: &str) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn collect_files_with_wildcard_recursive(pattern: &str) -> Result<Vec<PathBuf>> {
+fn collect_files_with_wildcard_recursive(pattern: &str, failures: Option<&failures::Failures>) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     
     // If pattern contains a path, extract the directory and pattern
@@ -259,17 +1384,17 @@ fn collect_files_with_wildcard_recursive(pattern: &str) -> Result<Vec<PathBuf>>
         
         let base_path = Path::new(base_dir);
         if base_path.is_dir() {
-            collect_files_recursive_with_pattern(base_path, file_pattern, &mut files)?;
+            collect_files_recursive_with_pattern(base_path, file_pattern, &mut files, failures)?;
         }
     } else {
         // Pattern like "*.txt" - search in current directory recursively
-        collect_files_recursive_with_pattern(Path::new("."), pattern, &mut files)?;
+        collect_files_recursive_with_pattern(Path::new("."), pattern, &mut files, failures)?;
     }
     
     Ok(files)
 }
 
-fn collect_files_recursive_with_pattern(dir: &Path, pattern: &str, files: &mut Vec<PathBuf>) -> Result<()> {
+fn collect_files_recursive_with_pattern(dir: &Path, pattern: &str, files: &mut Vec<PathBuf>, failures: Option<&failures::Failures>) -> Result<()> {
     for entry in fs::read_dir(dir)
         .with_context(|| format!("Failed to read directory: {}", dir.display()))? 
     {
@@ -278,16 +1403,18 @@ fn collect_files_recursive_with_pattern(dir: &Path, pattern: &str, files: &mut V
         
         if entry_path.is_file() {
             // Check if filename matches the pattern
-            if let Some(file_name) = entry_path.file_name() {
-                if let Some(file_name_str) = file_name.to_str() {
-                    if matches_pattern(file_name_str, pattern) {
-                        files.push(entry_path);
-                    }
-                }
+            if let Some(file_name) = entry_path.file_name()
+                && let Some(file_name_str) = file_name.to_str()
+                && matches_pattern(file_name_str, pattern)
+            {
+                files.push(entry_path);
             }
         } else if entry_path.is_dir() {
-            // Recursively search subdirectories
-            collect_files_recursive_with_pattern(&entry_path, pattern, files)?;
+            // Recursively search subdirectories; with --keep-going an unreadable one is skipped
+            let mut sub_files = Vec::new();
+            let result = collect_files_recursive_with_pattern(&entry_path, pattern, &mut sub_files, failures);
+            failures::skip_on_error(result, &entry_path, failures)?;
+            files.extend(sub_files);
         }
     }
     Ok(())
@@ -304,13 +1431,11 @@ fn matches_pattern(filename: &str, pattern: &str) -> bool {
         // Contains pattern
         let middle = &pattern[1..pattern.len()-1];
         filename.contains(middle)
-    } else if pattern.starts_with('*') {
+    } else if let Some(suffix) = pattern.strip_prefix('*') {
         // Ends with pattern
-        let suffix = &pattern[1..];
         filename.ends_with(suffix)
-    } else if pattern.ends_with('*') {
+    } else if let Some(prefix) = pattern.strip_suffix('*') {
         // Starts with pattern
-        let prefix = &pattern[..pattern.len()-1];
         filename.starts_with(prefix)
     } else {
         // Exact match
@@ -318,7 +1443,7 @@ fn matches_pattern(filename: &str, pattern: &str) -> bool {
     }
 }
 
-fn collect_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
+fn collect_files_recursive(dir: &Path, failures: Option<&failures::Failures>) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     
     for entry in fs::read_dir(dir)
@@ -330,8 +1455,8 @@ fn collect_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
         if entry_path.is_file() {
             files.push(entry_path);
         } else if entry_path.is_dir() {
-            // Recursively collect files from subdirectory
-            let sub_files = collect_files_recursive(&entry_path)?;
+            // Recursively collect files from subdirectory; with --keep-going an unreadable one is skipped
+            let sub_files = failures::skip_on_error(collect_files_recursive(&entry_path, failures), &entry_path, failures)?;
             files.extend(sub_files);
         }
     }
@@ -339,10 +1464,8 @@ fn collect_files_recursive(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn concatenate_files(files: &[PathBuf], output_path: &str) -> Result<()> {
-    let mut output = fs::File::create(output_path)
-        .with_context(|| format!("Failed to create output file: {}", output_path))?;
-    
+// Returns the output files written (several when the output is split into parts)
+fn concatenate_files(files: &[PathBuf], output_path: &str, options: &ConcatOptions) -> Result<Vec<PathBuf>> {
     // Create progress bar if we have enough files to make it worthwhile
     let progress = if files.len() > 3 {
         let pb = ProgressBar::new(files.len() as u64);
@@ -362,89 +1485,365 @@ fn concatenate_files(files: &[PathBuf], output_path: &str) -> Result<()> {
         None
     };
     
+    let outputs = if let Some(split) = &options.split {
+        parts::write_parts(files, output_path, options, split, progress.as_ref())?
+    } else {
+        let temporary_path = format!("{}.tmp", output_path);
+        let writing_path = if options.atomic { temporary_path.as_str() } else { output_path };
+        let output = if options.append {
+            OutputWriter::append(writing_path, options.compression)?
+        } else {
+            OutputWriter::create(writing_path, options.compression)?
+        };
+        let output = match options.format {
+            OutputFormat::Text => match (options.merge, options.csv, &options.merge_by_timestamp) {
+                (Some(settings), _, _) => merge::write_merged(output, files, options, settings, progress.as_ref())?,
+                (None, Some(mismatch), _) => table::write_csv(output, files, options, mismatch, progress.as_ref())?,
+                (None, None, Some(settings)) => logmerge::write_merged_logs(output, files, options, settings, progress.as_ref())?,
+                (None, None, None) => {
+                    let (output, entries) = write_concatenated(output, files, options, progress.as_ref())?;
+                    if let Some(index_path) = &options.index {
+                        let index = index::OutputIndex { output: relative_path(Path::new(output_path)), files: entries };
+                        index::write_index(index_path, &index)?;
+                    }
+                    output
+                }
+            },
+            OutputFormat::Markdown => markdown::write_markdown(output, files, options, progress.as_ref())?,
+            OutputFormat::Json | OutputFormat::Jsonl => json::write_json(output, files, options, progress.as_ref())?,
+            OutputFormat::Tar | OutputFormat::Zip => pack::write_archive(output, files, options, progress.as_ref())?,
+        };
+        output.finish()?;
+        if options.atomic {
+            fs::rename(writing_path, output_path)
+                .with_context(|| format!("Failed to move {} into place", writing_path))?;
+        }
+        vec![PathBuf::from(output_path)]
+    };
+    
+    // Finish progress bar and ensure it's properly cleaned up
+    if let Some(pb) = progress {
+        pb.finish_and_clear();
+    }
+    
+    Ok(outputs)
+}
+
+// Returns the output along with where each input landed in it
+fn write_concatenated<W: Write>(output: W, files: &[PathBuf], options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<(W, Vec<index::IndexEntry>)> {
+    let mut output = index::CountingWriter::new(output);
+    let mut entries = Vec::with_capacity(files.len());
+    let mut prefixer = lines::LinePrefixer::new(options.prefix_lines.as_deref(), options.number);
+    let header_names = if options.headers { bundle::entry_names(files)? } else { Vec::new() };
     for (index, file_path) in files.iter().enumerate() {
-        // Update progress bar
-        if let Some(ref pb) = progress {
-            let file_name = file_path.file_name()
-                .unwrap_or_default()
-                .to_string_lossy();
-            pb.set_message(format!("Processing {}", file_name));
-            pb.inc(1);
-            // Force immediate refresh
-            pb.tick();
+        let name = relative_path(file_path);
+
+        // With headers the content is written whole so it can be split again
+        if options.headers {
+            let Some((header, content)) = bundle_record(file_path, &header_names[index], index + 1, &mut prefixer, options, progress)? else {
+                continue;
+            };
+            output.write_all(header.as_bytes())?;
+            let start = output.position();
+            output.write_all(&content)
+                .with_context(|| format!("Failed to write content from file: {:?}", file_path))?;
+            entries.push(output.entry(&name, start));
+            writeln!(output)?;
+            continue;
         }
+
+        let Some((content, origins)) = read_numbered_input(file_path, options, progress)? else {
+            continue;
+        };
+        let content = prefixer.apply(file_path, index + 1, &content, &origins);
         
-        let content = read_file_with_encoding_detection(file_path)
-            .with_context(|| format!("Failed to read file: {:?}", file_path))?;
+        // Add newline between files (but not before the first file written)
+        if !entries.is_empty() {
+            writeln!(output)?;
+        }
         
         // Remove trailing newlines from content to avoid double newlines
         let trimmed_content = content.trim_end();
+        let start = output.position();
         output.write_all(trimmed_content.as_bytes())
             .with_context(|| format!("Failed to write content from file: {:?}", file_path))?;
-        
-        // Add newline between files (but not after the last file)
-        if index < files.len() - 1 {
-            writeln!(output)?;
+        entries.push(output.entry(&name, start));
+    }
+    Ok((output.into_inner(), entries))
+}
+
+// The header and content of an input written with --headers: text decoded to
+// UTF-8 with the content options applied, or for binary inputs and text that
+// no encoding decodes cleanly, the bytes as read, marked raw so that split
+// restores them unchanged. None as for read_numbered_input.
+fn bundle_record(
+    file_path: &Path,
+    name: &str,
+    index: usize,
+    prefixer: &mut lines::LinePrefixer,
+    options: &ConcatOptions,
+    progress: Option<&ProgressBar>,
+) -> Result<Option<(String, Vec<u8>)>> {
+    let Some(bytes) = read_input_bytes(file_path, options, progress)? else {
+        return Ok(None);
+    };
+    if is_binary(&bytes) {
+        return Ok(Some((bundle::header_line(name, bytes.len(), true), bytes)));
+    }
+    let (content, encoding) = decode_input(file_path, &bytes, options);
+    if encoding == LOSSY_UTF8 {
+        return Ok(Some((bundle::header_line(name, bytes.len(), true), bytes)));
+    }
+    let Some((content, origins)) = transform_numbered(file_path, content, options) else {
+        return Ok(None);
+    };
+    let content = prefixer.apply(file_path, index, &content, &origins);
+    Ok(Some((bundle::header_line(name, content.len(), false), content.into_bytes())))
+}
+
+// Read and decode the next input, updating the progress bar as we go.
+// None means the input failed to read and --keep-going skipped it, or that
+// --grep or --grep-v left it without any line.
+fn read_input_file(file_path: &Path, options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Option<String>> {
+    Ok(read_numbered_input(file_path, options, progress)?.map(|(content, _)| content))
+}
+
+// read_input_file, with the source line of each line of the result for
+// --prefix-lines and --number
+fn read_numbered_input(file_path: &Path, options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Option<(String, lines::Origins)>> {
+    let Some(bytes) = read_input_bytes(file_path, options, progress)? else {
+        return Ok(None);
+    };
+    Ok(transform_numbered(file_path, decode_input(file_path, &bytes, options).0, options))
+}
+
+// Decode an input returned by read_input_bytes, recording the encoding
+// detected for the run report and the manifest
+fn decode_input(file_path: &Path, bytes: &[u8], options: &ConcatOptions) -> (String, &'static str) {
+    let (content, encoding) = decode_with_encoding_detection(bytes);
+    if let Some(recorder) = &options.report {
+        recorder.record_decoded(file_path, encoding);
+    }
+    if let Some(recorder) = &options.manifest {
+        recorder.record_decoded(file_path, encoding);
+    }
+    (content, encoding)
+}
+
+// Apply the per-file content options to decoded text; None when --grep or
+// --grep-v kept no line of it
+fn transform_content(file_path: &Path, content: String, options: &ConcatOptions) -> Option<String> {
+    transform_numbered(file_path, content, options).map(|(content, _)| content)
+}
+
+fn transform_numbered(file_path: &Path, content: String, options: &ConcatOptions) -> Option<(String, lines::Origins)> {
+    let start = std::time::Instant::now();
+    let (content, origins) = transform_decoded(file_path, content, options)?;
+    if let Some(recorder) = &options.report {
+        recorder.record_transformed(file_path, content.len(), start.elapsed());
+    }
+    Some((content, origins))
+}
+
+// Whether transform_content changes the text of an input, so it has to be
+// read in whole
+fn transforms_content(file_path: &Path, options: &ConcatOptions) -> bool {
+    options.includes.is_some()
+        || options.redactor.is_some()
+        || (options.strip_comments && strip::comment_syntax(file_path).is_some())
+        || options.squeeze_blank
+        || options.line_selection.is_some()
+        || options.line_filter.is_some()
+        || options.token_limits.contains_key(file_path)
+}
+
+// Each transformation also maps the lines it keeps back to the source file,
+// so numbering does not count lines that were added, dropped or moved
+fn transform_decoded(file_path: &Path, content: String, options: &ConcatOptions) -> Option<(String, lines::Origins)> {
+    let origins = lines::source_origins(&content);
+    let (content, origins) = match &options.includes {
+        Some(includes) => includes.expand(file_path, &content, &origins),
+        None => (content, origins),
+    };
+    let (content, origins) = match &options.redactor {
+        Some(redactor) => redactor.redact(file_path, &content, &origins),
+        None => (content, origins),
+    };
+    let (content, origins) = match strip::comment_syntax(file_path) {
+        Some(syntax) if options.strip_comments => strip::strip_comments(&content, &origins, syntax),
+        _ => (content, origins),
+    };
+    let (content, origins) = if options.squeeze_blank { strip::squeeze_blank(&content, &origins) } else { (content, origins) };
+    let (content, origins) = match options.line_selection {
+        Some(selection) => lines::select_lines(&content, &origins, selection, options.elide),
+        None => (content, origins),
+    };
+    let (content, mut origins) = match &options.line_filter {
+        Some(filter) => filter.apply_to_file(file_path, &content, &origins)?,
+        None => (content, origins),
+    };
+    match options.token_limits.get(file_path) {
+        Some(&max_tokens) => {
+            let content = options.tokenizer.truncate(&content, max_tokens);
+            origins.truncate(content.split_inclusive('\n').count());
+            Some((content, origins))
         }
+        None => Some((content, origins)),
     }
-    
-    // Finish progress bar and ensure it's properly cleaned up
+}
+
+// Read the raw bytes of the next input (archive members and compressed files are
+// unpacked here), updating the progress bar as we go. With --keep-going a
+// failed read is recorded and gives None.
+fn read_input_bytes(file_path: &Path, options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Option<Vec<u8>>> {
+    advance_progress(file_path, progress);
+    let start = std::time::Instant::now();
+    let bytes = archive::read_input(file_path, options.decompress)
+        .with_context(|| format!("Failed to read file: {:?}", file_path))
+        .map(Some);
+    let Some(bytes) = failures::skip_on_error(bytes, file_path, options.keep_going.as_ref())? else {
+        return Ok(None);
+    };
+    if let Some(recorder) = &options.report {
+        recorder.record_read(file_path, &bytes, start.elapsed());
+    }
+    if let Some(recorder) = &options.manifest {
+        recorder.record(file_path, &bytes)?;
+    }
+    Ok(Some(bytes))
+}
+
+// Show the next input on the progress bar
+fn advance_progress(file_path: &Path, progress: Option<&ProgressBar>) {
     if let Some(pb) = progress {
-        pb.finish_and_clear();
+        let file_name = file_path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        pb.set_message(format!("Processing {}", file_name));
+        pb.inc(1);
+        // Force immediate refresh
+        pb.tick();
     }
-    
-    output.flush()
-        .with_context(|| "Failed to flush output file")?;
-    
-    Ok(())
 }
 
-fn read_file_with_encoding_detection(file_path: &PathBuf) -> Result<String> {
-    // Read the file as bytes first
-    let bytes = fs::read(file_path)
-        .with_context(|| format!("Failed to read file: {:?}", file_path))?;
-    
+// Path of an input relative to the current directory, with "/" separators.
+// Paths outside the current directory lose their root, as tar does.
+fn relative_path(path: &Path) -> String {
+    let current_dir = std::env::current_dir().ok();
+    let relative = current_dir.as_deref()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path);
+    relative.components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn normalize_line_endings(content: &str) -> String {
+    content.replace("\r\n", "\n")
+}
+
+// Treat content with NUL bytes as binary, unless it carries a UTF-16 BOM
+fn is_binary(bytes: &[u8]) -> bool {
+    let utf16_bom = bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]);
+    !utf16_bom && bytes.contains(&0) && utf16_without_bom(bytes).is_none()
+}
+
+// UTF-16 text without a BOM, recognized by its ASCII characters: at least
+// every other character is printable ASCII, with its NUL byte always on the
+// same side
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let ascii = |byte: u8| byte.is_ascii_graphic() || matches!(byte, b' ' | b'\t' | b'\n' | b'\r');
+    let units = bytes.chunks_exact(2);
+    let count = |high: usize| {
+        let nul_low = units.clone().any(|unit| unit[1 - high] == 0);
+        let ascii_units = units.clone().filter(|unit| unit[high] == 0 && ascii(unit[1 - high])).count();
+        if nul_low { 0 } else { ascii_units }
+    };
+    match (count(1), count(0)) {
+        (little, _) if little * 2 >= units.len() => Some(encoding_rs::UTF_16LE),
+        (_, big) if big * 2 >= units.len() => Some(encoding_rs::UTF_16BE),
+        _ => None,
+    }
+}
+
+// Bytes from the start of an input read as a stream (--merge-by-timestamp,
+// --follow) from which its encoding is detected
+const DETECTION_BYTES: usize = 64 * 1024;
+
+// Encoding of an input read as a stream, detected from its first
+// DETECTION_BYTES the way whole inputs are decoded
+fn detect_encoding(head: &[u8]) -> &'static encoding_rs::Encoding {
+    let head = &head[..head.len().min(DETECTION_BYTES)];
+    // The head may end inside a character
+    let head = match std::str::from_utf8(head) {
+        Err(error) if error.error_len().is_none() => &head[..error.valid_up_to()],
+        _ => head,
+    };
+    let (_, name) = decode_with_encoding_detection(head);
+    encoding_rs::Encoding::for_label(name.as_bytes()).unwrap_or(encoding_rs::UTF_8)
+}
+
+// Encoding reported for bytes that no encoding decodes without errors
+const LOSSY_UTF8: &str = "UTF-8 (lossy)";
+
+// Decode raw input bytes, returning the text and the name of the detected encoding
+fn decode_with_encoding_detection(bytes: &[u8]) -> (String, &'static str) {
     // Check for BOM first
     if bytes.len() >= 2 {
         // UTF-16 LE BOM
         if bytes[0] == 0xFF && bytes[1] == 0xFE {
             let (content, _, _) = encoding_rs::UTF_16LE.decode(&bytes[2..]);
-            return Ok(content.to_string());
+            return (content.to_string(), "UTF-16LE");
         }
         // UTF-16 BE BOM
         if bytes[0] == 0xFE && bytes[1] == 0xFF {
             let (content, _, _) = encoding_rs::UTF_16BE.decode(&bytes[2..]);
-            return Ok(content.to_string());
+            return (content.to_string(), "UTF-16BE");
         }
         // UTF-8 BOM
-        if bytes.len() >= 3 && bytes[0] == 0xEF && bytes[1] == 0xBB && bytes[2] == 0xBF {
-            if let Ok(utf8_content) = std::str::from_utf8(&bytes[3..]) {
-                return Ok(utf8_content.to_string());
-            }
+        if bytes.len() >= 3 && bytes[0] == 0xEF && bytes[1] == 0xBB && bytes[2] == 0xBF
+            && let Ok(utf8_content) = std::str::from_utf8(&bytes[3..])
+        {
+            return (utf8_content.to_string(), "UTF-8");
         }
     }
     
+    // NULs are valid UTF-8, so UTF-16 without a BOM has to be caught first
+    if let Some(encoding) = utf16_without_bom(bytes) {
+        let (content, has_errors) = encoding.decode_without_bom_handling(bytes);
+        if !has_errors {
+            return (content.to_string(), encoding.name());
+        }
+    }
+
     // Try UTF-8 (most common)
-    if let Ok(utf8_content) = std::str::from_utf8(&bytes) {
-        return Ok(utf8_content.to_string());
+    if let Ok(utf8_content) = std::str::from_utf8(bytes) {
+        return (utf8_content.to_string(), "UTF-8");
     }
     
     // Try UTF-16LE (common on Windows)
-    let (utf16le_content, _, utf16le_has_errors) = encoding_rs::UTF_16LE.decode(&bytes);
+    let (utf16le_content, _, utf16le_has_errors) = encoding_rs::UTF_16LE.decode(bytes);
     if !utf16le_has_errors {
-        return Ok(utf16le_content.to_string());
+        return (utf16le_content.to_string(), "UTF-16LE");
     }
     
     // Try UTF-16BE
-    let (utf16be_content, _, utf16be_has_errors) = encoding_rs::UTF_16BE.decode(&bytes);
+    let (utf16be_content, _, utf16be_has_errors) = encoding_rs::UTF_16BE.decode(bytes);
     if !utf16be_has_errors {
-        return Ok(utf16be_content.to_string());
+        return (utf16be_content.to_string(), "UTF-16BE");
     }
     
     // Try Windows-1252 (very common for Windows text files)
-    let (win_content, _, win_has_errors) = encoding_rs::WINDOWS_1252.decode(&bytes);
+    let (win_content, _, win_has_errors) = encoding_rs::WINDOWS_1252.decode(bytes);
     if !win_has_errors {
-        return Ok(win_content.to_string());
+        return (win_content.to_string(), "windows-1252");
     }
     
     // Try common ISO-8859 encodings that exist in encoding_rs
@@ -463,9 +1862,9 @@ fn read_file_with_encoding_detection(file_path: &PathBuf) -> Result<String> {
     ];
     
     for encoding in &iso_encodings {
-        let (content, _, has_errors) = encoding.decode(&bytes);
+        let (content, _, has_errors) = encoding.decode(bytes);
         if !has_errors {
-            return Ok(content.to_string());
+            return (content.to_string(), encoding.name());
         }
     }
     
@@ -481,14 +1880,14 @@ fn read_file_with_encoding_detection(file_path: &PathBuf) -> Result<String> {
     ];
     
     for encoding in &other_encodings {
-        let (content, _, has_errors) = encoding.decode(&bytes);
+        let (content, _, has_errors) = encoding.decode(bytes);
         if !has_errors {
-            return Ok(content.to_string());
+            return (content.to_string(), encoding.name());
         }
     }
     
     // Fallback: replace invalid UTF-8 sequences
-    Ok(String::from_utf8_lossy(&bytes).to_string())
+    (String::from_utf8_lossy(bytes).to_string(), LOSSY_UTF8)
 }
 
 #[cfg(test)]
@@ -503,7 +1902,7 @@ mod tests {
         let file_path = temp_dir.path().join("test.txt");
         fs::write(&file_path, "Hello, World!")?;
         
-        let files = resolve_input_files(file_path.to_str().unwrap(), false)?;
+        let files = resolve_input_files(file_path.to_str().unwrap(), false, None)?;
         assert_eq!(files.len(), 1);
         assert_eq!(files[0], file_path);
         Ok(())
@@ -521,7 +1920,7 @@ mod tests {
         // Create a subdirectory (should be ignored when not recursive)
         fs::create_dir(temp_dir.path().join("subdir"))?;
         
-        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false)?;
+        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false, None)?;
         assert_eq!(files.len(), 3);
         
         // Check that all expected files are present
@@ -548,7 +1947,7 @@ mod tests {
         let original_dir = std::env::current_dir()?;
         std::env::set_current_dir(temp_dir.path())?;
         
-        let files = resolve_input_files("*.txt", false)?;
+        let files = resolve_input_files("*.txt", false, None)?;
         assert_eq!(files.len(), 2);
         
         // Restore original directory
@@ -568,7 +1967,7 @@ mod tests {
         fs::write(&file1, "Hello")?;
         fs::write(&file2, "World")?;
         
-        concatenate_files(&[file1.clone(), file2.clone()], output.to_str().unwrap())?;
+        concatenate_files(&[file1.clone(), file2.clone()], output.to_str().unwrap(), &ConcatOptions::default())?;
         
         let result = fs::read_to_string(&output)?;
         assert_eq!(result, "Hello\nWorld");
@@ -584,23 +1983,110 @@ mod tests {
         
         fs::write(&file1, "Single content")?;
         
-        concatenate_files(&[file1.clone()], output.to_str().unwrap())?;
+        concatenate_files(std::slice::from_ref(&file1), output.to_str().unwrap(), &ConcatOptions::default())?;
         
         let result = fs::read_to_string(&output)?;
         assert_eq!(result, "Single content");
         Ok(())
     }
 
+    #[test]
+    fn test_prefixes_number_source_lines() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("app.log");
+        let output = temp_dir.path().join("output.txt");
+        fs::write(&file1, "x1\n\n\n\nb\nx2\nc\nx3\n")?;
+
+        // Squeezed blank lines and the cut head do not shift the numbers
+        let options = ConcatOptions {
+            squeeze_blank: true,
+            line_selection: Some(lines::LineSelection::Tail(3)),
+            line_filter: Some(lines::LineFilter { grep: Some(regex::Regex::new("x")?), ..Default::default() }),
+            prefix_lines: Some("{lineno}: ".to_string()),
+            ..Default::default()
+        };
+        concatenate_files(std::slice::from_ref(&file1), output.to_str().unwrap(), &options)?;
+        assert_eq!(fs::read_to_string(&output)?, "6: x2\n8: x3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_grep_skips_files_without_matching_lines() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let quiet = temp_dir.path().join("quiet.log");
+        let noisy = temp_dir.path().join("noisy.log");
+        let output = temp_dir.path().join("output.txt");
+        fs::write(&quiet, "INFO start\n")?;
+        fs::write(&noisy, "INFO start\nERROR failed\n")?;
+
+        // Skipped as they are read, with no separate pass over the inputs
+        let options = ConcatOptions {
+            line_filter: Some(lines::LineFilter { grep: Some(regex::Regex::new("ERROR")?), ..Default::default() }),
+            headers: true,
+            ..Default::default()
+        };
+        concatenate_files(&[noisy.clone(), quiet.clone()], output.to_str().unwrap(), &options)?;
+        let written = fs::read_to_string(&output)?;
+        assert!(written.contains("ERROR failed\n") && !written.contains("quiet.log"));
+        assert_eq!(options.line_filter.unwrap().unmatched(), std::collections::BTreeSet::from([quiet]));
+        Ok(())
+    }
+
     #[test]
     fn test_nonexistent_file() {
-        let result = resolve_input_files("/nonexistent/file.txt", false);
+        let result = resolve_input_files("/nonexistent/file.txt", false, None);
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_resolve_all_inputs_sorts_and_keeps_going() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::write(temp_dir.path().join("b.txt"), "b")?;
+        fs::write(temp_dir.path().join("a.txt"), "a")?;
+        let directory = temp_dir.path().to_str().unwrap().to_string();
+        let missing = temp_dir.path().join("missing.txt").to_str().unwrap().to_string();
+
+        assert!(resolve_all_inputs(&[&directory, &missing], false, None, false).is_err());
+        let failures = failures::Failures::default();
+        let files = resolve_all_inputs(&[&directory, &missing], false, Some(&failures), false)?;
+        assert_eq!(files, vec![temp_dir.path().join("a.txt"), temp_dir.path().join("b.txt")]);
+        assert_eq!(failures.list().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going_skips_inputs_that_fail_to_read() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        for (name, content) in [("a.txt", "a"), ("b.txt", "b"), ("c.txt", "c")] {
+            fs::write(temp_dir.path().join(name), content)?;
+        }
+        fs::write(temp_dir.path().join("d.gz"), "not gzip")?;
+        let directory = temp_dir.path().to_str().unwrap().to_string();
+        let output = temp_dir.path().join("out").join("output.txt");
+        fs::create_dir(temp_dir.path().join("out"))?;
+
+        let failures = failures::Failures::default();
+        let files = resolve_all_inputs(&[&directory], false, Some(&failures), false)?;
+        assert_eq!(files.len(), 4);
+        // The file vanishes between resolution and reading
+        fs::remove_file(temp_dir.path().join("b.txt"))?;
+
+        let options = ConcatOptions { decompress: true, ..Default::default() };
+        assert!(concatenate_files(&files, output.to_str().unwrap(), &options).is_err());
+
+        let options = ConcatOptions { decompress: true, keep_going: Some(failures.clone()), ..Default::default() };
+        concatenate_files(&files, output.to_str().unwrap(), &options)?;
+        assert_eq!(fs::read_to_string(&output)?, "a\nc");
+        let failed: Vec<String> = failures.list().into_iter().map(|(path, _)| path).collect();
+        assert!(failed[0].ends_with("b.txt") && failed[1].ends_with("d.gz"));
+        assert!(failures.contains(&temp_dir.path().join("d.gz")));
+        Ok(())
+    }
+
     #[test]
     fn test_empty_directory() -> Result<()> {
         let temp_dir = TempDir::new()?;
-        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false)?;
+        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false, None)?;
         assert_eq!(files.len(), 0);
         Ok(())
     }
@@ -627,7 +2113,7 @@ mod tests {
         fs::write(temp_dir.path().join("subdir2").join("sub3.txt"), "Sub content 3")?;
         
         // Test recursive collection
-        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), true)?;
+        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), true, None)?;
         assert_eq!(files.len(), 6);
         
         // Check that files from all directories are included
@@ -656,7 +2142,7 @@ mod tests {
         fs::write(temp_dir.path().join("subdir1").join("sub1.txt"), "Sub content 1")?;
         
         // Test non-recursive collection (should only get root files)
-        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false)?;
+        let files = resolve_input_files(temp_dir.path().to_str().unwrap(), false, None)?;
         assert_eq!(files.len(), 1);
         
         let file_names: Vec<String> = files.iter()
@@ -690,15 +2176,15 @@ mod tests {
         std::env::set_current_dir(temp_dir.path())?;
         
         // Test recursive wildcard for .txt files
-        let files = resolve_input_files("*.txt", true)?;
+        let files = resolve_input_files("*.txt", true, None)?;
         assert_eq!(files.len(), 3); // root1.txt, sub1.txt, nested.txt
         
         // Test recursive wildcard for .log files
-        let log_files = resolve_input_files("*.log", true)?;
+        let log_files = resolve_input_files("*.log", true, None)?;
         assert_eq!(log_files.len(), 2); // root2.log, sub2.log
         
         // Test non-recursive wildcard (should only get root files)
-        let non_recursive_files = resolve_input_files("*.txt", false)?;
+        let non_recursive_files = resolve_input_files("*.txt", false, None)?;
         // Filter to only include files that are actually in our temp directory
         let temp_dir_files: Vec<_> = non_recursive_files.iter()
             .filter(|path| path.starts_with(temp_dir.path()))
@@ -727,7 +2213,7 @@ mod tests {
         
         // Test recursive wildcard with path
         let pattern = format!("{}/*.rs", temp_dir.path().join("src").display());
-        let files = resolve_input_files(&pattern, true)?;
+        let files = resolve_input_files(&pattern, true, None)?;
         assert_eq!(files.len(), 3); // main.rs, utils.rs, module.rs
         
         Ok(())
@@ -788,7 +2274,7 @@ mod tests {
         ];
         
         let output_path = temp_dir.path().join("output.txt");
-        concatenate_files(&files, output_path.to_str().unwrap())?;
+        concatenate_files(&files, output_path.to_str().unwrap(), &ConcatOptions::default())?;
         
         let result = fs::read_to_string(&output_path)?;
         
@@ -796,7 +2282,90 @@ mod tests {
         assert!(result.contains("Hello, 世界!"));
         assert!(result.contains("Hello,"));
         assert!(result.contains("UTF-16LE"));
+
+        // Without a BOM, UTF-16 is told apart from binary content by its NULs
+        let utf16be: Vec<u8> = "Hi there".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert!(!is_binary(&utf16be));
+        assert_eq!(decode_with_encoding_detection(&utf16be), ("Hi there".to_string(), "UTF-16BE"));
+        assert!(is_binary(&[0x7F, b'E', b'L', b'F', 0x02, 0x01, 0x01, 0x00, 0x00, 0x00]));
         
         Ok(())
     }
+
+    #[test]
+    fn test_resolve_and_concatenate_archive_members() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let archive_path = temp_dir.path().join("bundle.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive_path)?);
+        for (name, content) in [("logs/a.log", "First log"), ("logs/b.log", "Second log"), ("notes.txt", "Skip me")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, content.as_bytes())?;
+        }
+        builder.finish()?;
+
+        let mut files = resolve_input_files(&format!("{}//**/*.log", archive_path.display()), false, None)?;
+        files.sort();
+        assert_eq!(files.len(), 2);
+
+        let output = temp_dir.path().join("output.txt");
+        concatenate_files(&files, output.to_str().unwrap(), &ConcatOptions::default())?;
+        assert_eq!(fs::read_to_string(&output)?, "First log\nSecond log");
+
+        // A bare archive, or one found in a directory, reads as all its members
+        assert_eq!(resolve_input_files(archive_path.to_str().unwrap(), false, None)?.len(), 3);
+        assert_eq!(resolve_input_files(temp_dir.path().to_str().unwrap(), false, None)?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concatenate_compressed_output() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("file1.txt");
+        let file2 = temp_dir.path().join("file2.txt");
+        let output = temp_dir.path().join("output.txt.gz");
+        fs::write(&file1, "Hello")?;
+        fs::write(&file2, "World")?;
+
+        let options = ConcatOptions {
+            compression: Some(CompressionSettings::new(archive::Compression::Gzip, None, 0)?),
+            ..Default::default()
+        };
+        concatenate_files(&[file1, file2], output.to_str().unwrap(), &options)?;
+
+        let decoded = archive::read_input(&output, true)?;
+        assert_eq!(decoded, b"Hello\nWorld");
+        Ok(())
+    }
+
+    #[test]
+    fn test_headers_round_trip_through_split() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source = temp_dir.path().join("source");
+        fs::create_dir_all(source.join("nested"))?;
+        fs::write(source.join("a.txt"), "Alpha\n\n")?;
+        fs::write(source.join("nested").join("b.txt"), "==> fake (1 bytes) <==\nBeta")?;
+        // Binary content is written as read rather than decoded
+        fs::write(source.join("c.bin"), [0x89, b'P', b'N', b'G', 0x00, 0xFF, b'\n'])?;
+
+        let mut files = resolve_input_files(source.to_str().unwrap(), true, None)?;
+        files.sort();
+        let bundle_path = temp_dir.path().join("bundle.txt");
+        let options = ConcatOptions { headers: true, ..Default::default() };
+        concatenate_files(&files, bundle_path.to_str().unwrap(), &options)?;
+
+        let restored_dir = temp_dir.path().join("restored");
+        let restored = bundle::split_bundle(&bundle_path, &restored_dir)?;
+        // Inputs outside the current directory are restored relative to their
+        // common directory
+        assert_eq!(restored, vec![restored_dir.join("a.txt"), restored_dir.join("c.bin"), restored_dir.join("nested/b.txt")]);
+        let header = b"==> c.bin (7 raw bytes) <==\n";
+        assert!(fs::read(&bundle_path)?.windows(header.len()).any(|window| window == header));
+        for (original, restored) in files.iter().zip(&restored) {
+            assert_eq!(fs::read(original)?, fs::read(restored)?);
+        }
+        Ok(())
+    }
 }
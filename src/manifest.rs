@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::timestamp::{format_rfc3339, unix_seconds};
+use crate::{archive, decode_with_encoding_detection, is_binary};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputRecord {
+    // Path as resolved, relative to the manifest's directory unless absolute
+    pub path: String,
+    pub size: u64,
+    pub mtime: String,
+    pub encoding: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputRecord {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    // Working directory the paths are relative to
+    pub directory: String,
+    // Inputs were read with --decompress
+    pub decompress: bool,
+    pub inputs: Vec<InputRecord>,
+    pub outputs: Vec<OutputRecord>,
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Encoding of an input read only for the manifest; inputs read for the output
+// get theirs from the decode of the output
+fn encoding_of(bytes: &[u8]) -> &'static str {
+    if is_binary(bytes) { "binary" } else { decode_with_encoding_detection(bytes).1 }
+}
+
+fn describe_input(file_path: &Path, size: u64, encoding: &str, sha256: String) -> Result<InputRecord> {
+    let modified = archive::input_metadata(file_path)?.modified()?;
+    Ok(InputRecord {
+        path: file_path.display().to_string(),
+        size,
+        mtime: format_rfc3339(unix_seconds(modified)),
+        encoding: encoding.to_string(),
+        sha256,
+    })
+}
+
+fn input_record(file_path: &Path, decompress: bool) -> Result<InputRecord> {
+    let bytes = archive::read_input(file_path, decompress)?;
+    describe_input(file_path, bytes.len() as u64, encoding_of(&bytes), sha256_hex(&bytes))
+}
+
+// Describes each input from the bytes read for concatenation, so the manifest
+// records exactly what went into the output without reading it again
+#[derive(Debug, Clone, Default)]
+pub struct InputRecorder(Arc<Mutex<BTreeMap<PathBuf, InputRecord>>>);
+
+impl InputRecorder {
+    // The encoding of text is left empty until record_decoded fills it in
+    pub fn record(&self, file_path: &Path, bytes: &[u8]) -> Result<()> {
+        self.record_digest(file_path, bytes.len() as u64, if is_binary(bytes) { "binary" } else { "" }, sha256_hex(bytes))
+    }
+
+    // Binary inputs keep their "binary" encoding when the output decodes them
+    pub fn record_decoded(&self, file_path: &Path, encoding: &str) {
+        if let Some(record) = self.0.lock().unwrap().get_mut(file_path)
+            && record.encoding.is_empty()
+        {
+            record.encoding = encoding.to_string();
+        }
+    }
+
+    // For inputs hashed while they were streamed
+    pub fn record_digest(&self, file_path: &Path, size: u64, encoding: &str, sha256: String) -> Result<()> {
+        let record = describe_input(file_path, size, encoding, sha256)?;
+        self.0.lock().unwrap().insert(file_path.to_path_buf(), record);
+        Ok(())
+    }
+
+    // Inputs read but never decoded (markdown omits text that is too large)
+    // have no encoding yet and are described again
+    fn input(&self, file_path: &Path) -> Option<InputRecord> {
+        self.0.lock().unwrap().get(file_path).filter(|record| !record.encoding.is_empty()).cloned()
+    }
+}
+
+// The output is hashed as it is read, without holding it in memory
+pub fn output_record(output_path: &Path) -> Result<OutputRecord> {
+    let mut file = fs::File::open(output_path).with_context(|| format!("Failed to read output: {}", output_path.display()))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read output: {}", output_path.display()))?;
+    Ok(OutputRecord { path: output_path.display().to_string(), size, sha256: hex(&hasher.finalize()) })
+}
+
+// Record the raw bytes of every input (before decoding or any transformation)
+// and of every output written. Inputs come from what was recorded while
+// reading them; any input not recorded is read here.
+pub fn build_manifest(files: &[PathBuf], outputs: &[PathBuf], decompress: bool, recorded: &InputRecorder) -> Result<Manifest> {
+    Ok(Manifest {
+        directory: std::env::current_dir()?.display().to_string(),
+        decompress,
+        inputs: files.iter()
+            .map(|file_path| recorded.input(file_path).map(Ok).unwrap_or_else(|| input_record(file_path, decompress)))
+            .collect::<Result<_>>()?,
+        outputs: outputs.iter().map(|output_path| output_record(output_path)).collect::<Result<_>>()?,
+    })
+}
+
+pub fn write_manifest(manifest_path: &Path, manifest: &Manifest) -> Result<()> {
+    fs::write(manifest_path, serde_json::to_string_pretty(manifest)? + "\n")
+        .with_context(|| format!("Failed to write manifest: {}", manifest_path.display()))
+}
+
+// Differences between the manifest and the files as they are now, one message
+// per drifted input or output
+pub fn verify_manifest(manifest: &Manifest) -> Vec<String> {
+    let directory = Path::new(&manifest.directory);
+    let mut drift = Vec::new();
+
+    for expected in &manifest.inputs {
+        match input_record(&directory.join(&expected.path), manifest.decompress) {
+            Err(error) => drift.push(format!("input {}: cannot be read ({:#})", expected.path, error)),
+            Ok(actual) if actual.sha256 != expected.sha256 || actual.size != expected.size => drift.push(format!(
+                "input {}: content changed ({} bytes, sha256 {} instead of {} bytes, sha256 {})",
+                expected.path, actual.size, actual.sha256, expected.size, expected.sha256
+            )),
+            Ok(actual) if actual.encoding != expected.encoding => drift.push(format!(
+                "input {}: encoding changed ({} instead of {})",
+                expected.path, actual.encoding, expected.encoding
+            )),
+            Ok(_) => {}
+        }
+    }
+    for expected in &manifest.outputs {
+        match output_record(&directory.join(&expected.path)) {
+            Err(error) => drift.push(format!("output {}: cannot be read ({:#})", expected.path, error)),
+            Ok(actual) if actual.sha256 != expected.sha256 || actual.size != expected.size => drift.push(format!(
+                "output {}: content changed ({} bytes, sha256 {} instead of {} bytes, sha256 {})",
+                expected.path, actual.size, actual.sha256, expected.size, expected.sha256
+            )),
+            Ok(_) => {}
+        }
+    }
+    drift
+}
+
+pub fn read_manifest(manifest_path: &Path) -> Result<Manifest> {
+    let json = fs::read_to_string(manifest_path).with_context(|| format!("Failed to read manifest: {}", manifest_path.display()))?;
+    serde_json::from_str(&json).with_context(|| format!("Invalid manifest: {}", manifest_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_manifest_detects_drift() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("a.txt");
+        let output = temp_dir.path().join("out.txt");
+        fs::write(&input, "hello\n")?;
+        fs::write(&output, "hello")?;
+
+        let manifest = build_manifest(std::slice::from_ref(&input), std::slice::from_ref(&output), false, &InputRecorder::default())?;
+        assert_eq!(manifest.inputs[0].size, 6);
+        assert_eq!(manifest.inputs[0].encoding, "UTF-8");
+        assert_eq!(manifest.inputs[0].sha256, sha256_hex(b"hello\n"));
+        assert!(verify_manifest(&manifest).is_empty());
+
+        fs::write(&input, "hello!\n")?;
+        fs::remove_file(&output)?;
+        let drift = verify_manifest(&manifest);
+        assert_eq!(drift.len(), 2);
+        assert!(drift[0].starts_with("input") && drift[0].contains("content changed"));
+        assert!(drift[1].starts_with("output") && drift[1].contains("cannot be read"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_uses_recorded_inputs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("a.txt");
+        fs::write(&input, "as concatenated\n")?;
+        let recorded = InputRecorder::default();
+        recorded.record(&input, b"as concatenated\n")?;
+        recorded.record_decoded(&input, "windows-1252");
+
+        // A change after reading does not leak into the manifest
+        fs::write(&input, "changed since\n")?;
+        let manifest = build_manifest(std::slice::from_ref(&input), &[], false, &recorded)?;
+        assert_eq!(manifest.inputs[0].sha256, sha256_hex(b"as concatenated\n"));
+        assert_eq!(manifest.inputs[0].size, 16);
+        assert_eq!(manifest.inputs[0].encoding, "windows-1252");
+
+        // An input read but never decoded is described from the file
+        let undecoded = InputRecorder::default();
+        undecoded.record(&input, b"as concatenated\n")?;
+        let manifest = build_manifest(std::slice::from_ref(&input), &[], false, &undecoded)?;
+        assert_eq!(manifest.inputs[0].encoding, "UTF-8");
+        assert_eq!(manifest.inputs[0].size, 14);
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use indicatif::ProgressBar;
+
+use crate::{decode_input, is_binary, read_input_bytes, relative_path, transform_content, ConcatOptions};
+
+// Fence language for a file, inferred from its name or extension
+pub fn fence_language(path: &Path) -> &'static str {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    match file_name.as_str() {
+        "dockerfile" => return "dockerfile",
+        "makefile" | "gnumakefile" => return "makefile",
+        "cmakelists.txt" => return "cmake",
+        _ => {}
+    }
+
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "swift" => "swift",
+        "rb" => "ruby",
+        "php" => "php",
+        "pl" | "pm" => "perl",
+        "lua" => "lua",
+        "r" => "r",
+        "sh" | "bash" | "zsh" => "bash",
+        "ps1" => "powershell",
+        "bat" | "cmd" => "batch",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "xml" | "svg" => "xml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "ini" | "cfg" => "ini",
+        "md" | "markdown" => "markdown",
+        "tex" => "latex",
+        "vue" => "vue",
+        "dart" => "dart",
+        "ex" | "exs" => "elixir",
+        "erl" => "erlang",
+        "hs" => "haskell",
+        "ml" | "mli" => "ocaml",
+        "zig" => "zig",
+        "proto" => "protobuf",
+        "graphql" | "gql" => "graphql",
+        "diff" | "patch" => "diff",
+        "csv" => "csv",
+        _ => "",
+    }
+}
+
+// A fence one backtick longer than the longest run of backticks in the content,
+// so the content can never close the block early
+pub fn fence_for(content: &str) -> String {
+    let mut longest = 0;
+    let mut current = 0;
+    for character in content.chars() {
+        if character == '`' {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    "`".repeat((longest + 1).max(3))
+}
+
+#[derive(Default)]
+struct TreeNode {
+    children: BTreeMap<String, TreeNode>,
+}
+
+// Render relative paths as an indented tree, directories suffixed with "/"
+pub fn directory_tree(paths: &[String]) -> String {
+    let mut root = TreeNode::default();
+    for path in paths {
+        let mut node = &mut root;
+        for part in path.split('/') {
+            node = node.children.entry(part.to_string()).or_default();
+        }
+    }
+
+    fn render(node: &TreeNode, prefix: &str, tree: &mut String) {
+        let count = node.children.len();
+        for (index, (name, child)) in node.children.iter().enumerate() {
+            let last = index + 1 == count;
+            let suffix = if child.children.is_empty() { "" } else { "/" };
+            tree.push_str(&format!("{}{}{}{}\n", prefix, if last { "└── " } else { "├── " }, name, suffix));
+            render(child, &format!("{}{}", prefix, if last { "    " } else { "│   " }), tree);
+        }
+    }
+
+    let mut tree = String::new();
+    render(&root, "", &mut tree);
+    tree
+}
+
+// Why an input is left out of the document, with only a note in its place
+pub fn omission(bytes: &[u8], options: &ConcatOptions) -> Option<&'static str> {
+    if is_binary(bytes) {
+        Some("binary file")
+    } else if options.max_file_size.is_some_and(|max_size| bytes.len() as u64 > max_size) {
+        Some("file too large")
+    } else {
+        None
+    }
+}
+
+// Write the inputs as a markdown document: an optional directory tree followed
+// by a "### path" section with a fenced code block per file. Binary and
+// oversized files keep their section but their content is omitted.
+pub fn write_markdown<W: Write>(mut output: W, files: &[PathBuf], options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<W> {
+    if !options.tree {
+        write_sections(&mut output, files, options, progress)?;
+    } else if options.line_filter.is_some() {
+        // Which files --grep or --grep-v leaves without lines is only known
+        // once they are read, so the tree is drawn after the sections
+        let mut sections = Vec::new();
+        let written = write_sections(&mut sections, files, options, progress)?;
+        write_tree(&mut output, &written)?;
+        output.write_all(&sections)?;
+    } else {
+        write_tree(&mut output, files)?;
+        write_sections(&mut output, files, options, progress)?;
+    }
+    Ok(output)
+}
+
+fn write_tree<W: Write>(output: &mut W, files: &[PathBuf]) -> Result<()> {
+    let paths: Vec<String> = files.iter().map(|path| relative_path(path)).collect();
+    let tree = directory_tree(&paths);
+    write!(output, "## Directory tree\n\n{fence}text\n{tree}{fence}\n\n", fence = fence_for(&tree), tree = tree)?;
+    Ok(())
+}
+
+// Write a section per input, returning the inputs written
+fn write_sections<W: Write>(output: &mut W, files: &[PathBuf], options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::with_capacity(files.len());
+    for file_path in files {
+        let Some(bytes) = read_input_bytes(file_path, options, progress)? else {
+            continue;
+        };
+        if let Some(reason) = omission(&bytes, options) {
+            write!(output, "### {}\n\n", relative_path(file_path))?;
+            write!(output, "_Omitted: {} ({} bytes)_\n\n", reason, bytes.len())?;
+            written.push(file_path.clone());
+            continue;
+        }
+
+        let (content, _) = decode_input(file_path, &bytes, options);
+        let Some(content) = transform_content(file_path, content, options) else {
+            continue;
+        };
+        write!(output, "### {}\n\n", relative_path(file_path))?;
+        let content = content.trim_end();
+        let fence = fence_for(content);
+        writeln!(output, "{}{}", fence, fence_language(file_path))?;
+        if !content.is_empty() {
+            writeln!(output, "{}", content)?;
+        }
+        write!(output, "{}\n\n", fence)?;
+        written.push(file_path.clone());
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_fence_language() {
+        assert_eq!(fence_language(Path::new("src/main.rs")), "rust");
+        assert_eq!(fence_language(Path::new("deploy/Dockerfile")), "dockerfile");
+        assert_eq!(fence_language(Path::new("config.YML")), "yaml");
+        assert_eq!(fence_language(Path::new("notes.unknown")), "");
+    }
+
+    #[test]
+    fn test_fence_grows_with_backticks() {
+        assert_eq!(fence_for("no ticks"), "```");
+        assert_eq!(fence_for("inline `code`"), "```");
+        assert_eq!(fence_for("```rust\nfn main() {}\n```"), "````");
+        assert_eq!(fence_for("`````"), "``````");
+    }
+
+    #[test]
+    fn test_directory_tree() {
+        let paths = vec!["src/main.rs".to_string(), "src/util/io.rs".to_string(), "README.md".to_string()];
+        assert_eq!(
+            directory_tree(&paths),
+            "├── README.md\n└── src/\n    ├── main.rs\n    └── util/\n        └── io.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_write_markdown() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let code = temp_dir.path().join("lib.rs");
+        let readme = temp_dir.path().join("notes.md");
+        let binary = temp_dir.path().join("image.png");
+        fs::write(&code, "fn main() {}\n")?;
+        fs::write(&readme, "Example:\n```sh\nls\n```\n")?;
+        fs::write(&binary, [0x89, b'P', b'N', b'G', 0x00, 0x01])?;
+
+        let options = ConcatOptions { tree: true, ..Default::default() };
+        let output = write_markdown(Vec::new(), &[code.clone(), readme.clone(), binary.clone()], &options, None)?;
+        let markdown = String::from_utf8(output)?;
+
+        assert!(markdown.starts_with("## Directory tree\n\n```text\n"));
+        assert!(markdown.contains(&format!("### {}\n\n```rust\nfn main() {{}}\n```\n\n", relative_path(&code))));
+        assert!(markdown.contains("````markdown\nExample:\n```sh\nls\n```\n````\n"));
+        assert!(markdown.contains(&format!("### {}\n\n_Omitted: binary file (6 bytes)_", relative_path(&binary))));
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{read_input_file, relative_path, ConcatOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeFormat {
+    // Top-level arrays are concatenated, top-level objects merged
+    Json,
+    // Every line is validated as a JSON value
+    Jsonl,
+    // Documents are joined with "---"
+    Yaml,
+}
+
+impl MergeFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(MergeFormat::Json),
+            "jsonl" | "ndjson" => Ok(MergeFormat::Jsonl),
+            "yaml" | "yml" => Ok(MergeFormat::Yaml),
+            _ => Err(anyhow::anyhow!("Unknown merge format: {}", name)),
+        }
+    }
+}
+
+// How top-level JSON objects are combined
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ObjectMerge {
+    // Nested objects are merged key by key
+    #[default]
+    Deep,
+    // Later files replace whole top-level keys
+    Shallow,
+}
+
+impl ObjectMerge {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "deep" => Ok(ObjectMerge::Deep),
+            "shallow" => Ok(ObjectMerge::Shallow),
+            _ => Err(anyhow::anyhow!("Unknown object merge strategy: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergeSettings {
+    pub format: MergeFormat,
+    pub objects: ObjectMerge,
+}
+
+// serde_json appends " at line L column C" to its messages; report the position
+// ourselves so JSONL errors can point at the line of the file instead
+fn json_error(file_path: &Path, error: &serde_json::Error, line: usize) -> anyhow::Error {
+    let message = error.to_string();
+    let suffix = format!(" at line {} column {}", error.line(), error.column());
+    anyhow::anyhow!(
+        "Invalid JSON in {} at line {}, column {}: {}",
+        relative_path(file_path),
+        line,
+        error.column(),
+        message.strip_suffix(&suffix).unwrap_or(&message)
+    )
+}
+
+fn yaml_error(file_path: &Path, error: &serde_yaml::Error) -> anyhow::Error {
+    match error.location() {
+        Some(location) => {
+            let message = error.to_string();
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            anyhow::anyhow!(
+                "Invalid YAML in {} at line {}, column {}: {}",
+                relative_path(file_path),
+                location.line(),
+                location.column(),
+                message.strip_suffix(&suffix).unwrap_or(&message)
+            )
+        }
+        None => anyhow::anyhow!("Invalid YAML in {}: {}", relative_path(file_path), error),
+    }
+}
+
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn deep_merge(target: &mut Value, source: Value) {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (key, value) in source {
+                match target.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+// Combine the top-level values of every file: arrays are concatenated in input
+// order, objects merged with later files taking precedence
+fn merge_json(files: &[PathBuf], options: &ConcatOptions, objects: ObjectMerge, progress: Option<&ProgressBar>) -> Result<Value> {
+    let mut merged: Option<(Value, &Path)> = None;
+    for file_path in files {
+        let Some(content) = read_input_file(file_path, options, progress)? else {
+            continue;
+        };
+        let value: Value = serde_json::from_str(&content)
+            .map_err(|error| json_error(file_path, &error, error.line()))?;
+
+        let Some((current, first)) = &mut merged else {
+            if !value.is_array() && !value.is_object() {
+                return Err(anyhow::anyhow!(
+                    "Cannot merge {}: top-level value is {}, expected an array or an object",
+                    relative_path(file_path),
+                    value_kind(&value)
+                ));
+            }
+            merged = Some((value, file_path));
+            continue;
+        };
+        match (current, value) {
+            (Value::Array(current), Value::Array(items)) => current.extend(items),
+            (Value::Object(current), Value::Object(entries)) if objects == ObjectMerge::Shallow => current.extend(entries),
+            (current @ Value::Object(_), value @ Value::Object(_)) => deep_merge(current, value),
+            (current, value) => {
+                return Err(anyhow::anyhow!(
+                    "Cannot merge {}: top-level value is {}, but {} holds {}",
+                    relative_path(file_path),
+                    value_kind(&value),
+                    relative_path(first),
+                    value_kind(current)
+                ));
+            }
+        }
+    }
+    Ok(merged.map(|(value, _)| value).unwrap_or(Value::Null))
+}
+
+// Write the inputs merged as a single JSON value, a validated JSON Lines stream,
+// or a YAML stream with one or more documents per file
+pub fn write_merged<W: Write>(mut output: W, files: &[PathBuf], options: &ConcatOptions, settings: MergeSettings, progress: Option<&ProgressBar>) -> Result<W> {
+    match settings.format {
+        MergeFormat::Json => {
+            let merged = merge_json(files, options, settings.objects, progress)?;
+            serde_json::to_writer_pretty(&mut output, &merged).context("Failed to write merged JSON")?;
+            writeln!(output)?;
+        }
+        MergeFormat::Jsonl => {
+            for file_path in files {
+                let Some(content) = read_input_file(file_path, options, progress)? else {
+                    continue;
+                };
+                for (index, line) in content.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    serde_json::from_str::<Value>(line)
+                        .map_err(|error| json_error(file_path, &error, index + 1))?;
+                    writeln!(output, "{}", line.trim_end())?;
+                }
+            }
+        }
+        MergeFormat::Yaml => {
+            let mut documents = 0;
+            for file_path in files {
+                let Some(content) = read_input_file(file_path, options, progress)? else {
+                    continue;
+                };
+                for document in serde_yaml::Deserializer::from_str(&content) {
+                    serde_yaml::Value::deserialize(document).map_err(|error| yaml_error(file_path, &error))?;
+                }
+
+                let content = content.trim();
+                if content.is_empty() {
+                    continue;
+                }
+                // A file may already open with its own document marker
+                if documents > 0 && !content.starts_with("---") {
+                    writeln!(output, "---")?;
+                }
+                writeln!(output, "{}", content)?;
+                documents += 1;
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write_input;
+    use tempfile::TempDir;
+
+    fn merge(files: &[PathBuf], format: MergeFormat, objects: ObjectMerge) -> Result<String> {
+        let settings = MergeSettings { format, objects };
+        Ok(String::from_utf8(write_merged(Vec::new(), files, &ConcatOptions::default(), settings, None)?)?)
+    }
+
+    #[test]
+    fn test_merge_json_arrays() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![
+            write_input(&temp_dir, "a.json", "[1, 2]\n")?,
+            write_input(&temp_dir, "b.json", "[{\"id\": 3}]")?,
+        ];
+        let merged: Value = serde_json::from_str(&merge(&files, MergeFormat::Json, ObjectMerge::Deep)?)?;
+        assert_eq!(merged, serde_json::json!([1, 2, {"id": 3}]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_json_objects() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![
+            write_input(&temp_dir, "a.json", r#"{"name": "a", "db": {"host": "localhost", "port": 5432}}"#)?,
+            write_input(&temp_dir, "b.json", r#"{"db": {"port": 6543}, "debug": true}"#)?,
+        ];
+
+        let deep: Value = serde_json::from_str(&merge(&files, MergeFormat::Json, ObjectMerge::Deep)?)?;
+        assert_eq!(deep, serde_json::json!({"name": "a", "db": {"host": "localhost", "port": 6543}, "debug": true}));
+
+        let shallow: Value = serde_json::from_str(&merge(&files, MergeFormat::Json, ObjectMerge::Shallow)?)?;
+        assert_eq!(shallow, serde_json::json!({"name": "a", "db": {"port": 6543}, "debug": true}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_errors_name_file_and_position() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let good = write_input(&temp_dir, "good.json", "[1]")?;
+        let bad = write_input(&temp_dir, "bad.json", "[1,\n  oops]")?;
+        let error = merge(&[good.clone(), bad], MergeFormat::Json, ObjectMerge::Deep).unwrap_err().to_string();
+        assert!(error.contains("bad.json at line 2, column 3"), "{}", error);
+
+        let object = write_input(&temp_dir, "object.json", "{}")?;
+        let error = merge(&[good, object], MergeFormat::Json, ObjectMerge::Deep).unwrap_err().to_string();
+        assert!(error.contains("object.json") && error.contains("an object"), "{}", error);
+
+        let lines = write_input(&temp_dir, "events.jsonl", "{\"a\": 1}\n\n{\"b\": }\n")?;
+        let error = merge(&[lines], MergeFormat::Jsonl, ObjectMerge::Deep).unwrap_err().to_string();
+        assert!(error.contains("events.jsonl at line 3, column 7"), "{}", error);
+
+        let yaml = write_input(&temp_dir, "bad.yaml", "key: [unclosed\n")?;
+        let error = merge(&[yaml], MergeFormat::Yaml, ObjectMerge::Deep).unwrap_err().to_string();
+        assert!(error.contains("Invalid YAML in") && error.contains("bad.yaml at line"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_jsonl_and_yaml() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let lines = vec![
+            write_input(&temp_dir, "a.jsonl", "{\"a\": 1}\n\n")?,
+            write_input(&temp_dir, "b.jsonl", "{\"b\": 2}")?,
+        ];
+        assert_eq!(merge(&lines, MergeFormat::Jsonl, ObjectMerge::Deep)?, "{\"a\": 1}\n{\"b\": 2}\n");
+
+        let documents = vec![
+            write_input(&temp_dir, "a.yaml", "a: 1\n")?,
+            write_input(&temp_dir, "b.yaml", "b: 2\n")?,
+            write_input(&temp_dir, "c.yaml", "---\nc: 3\n---\nd: 4\n")?,
+        ];
+        assert_eq!(merge(&documents, MergeFormat::Yaml, ObjectMerge::Deep)?, "a: 1\n---\nb: 2\n---\nc: 3\n---\nd: 4\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+
+use crate::archive::{self, ArchiveKind, Compression};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    // Flat concatenation of the decoded inputs
+    #[default]
+    Text,
+    // A "### path" section with a fenced code block per input
+    Markdown,
+    // One archive entry per input, under its relative path
+    Tar,
+    Zip,
+    // A JSON array with one object per input
+    Json,
+    // One JSON object per line
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "text" => Ok(OutputFormat::Text),
+            "markdown" | "md" => Ok(OutputFormat::Markdown),
+            "tar" => Ok(OutputFormat::Tar),
+            "zip" => Ok(OutputFormat::Zip),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            _ => Err(anyhow::anyhow!("Unknown output format: {}", name)),
+        }
+    }
+
+    // Pick the format from the output extension (.tar, .tar.gz, .tgz, .zip, ...)
+    pub fn infer(path: &Path) -> Self {
+        match archive::archive_kind(path) {
+            Some(ArchiveKind::Tar(_)) => OutputFormat::Tar,
+            Some(ArchiveKind::Zip) => OutputFormat::Zip,
+            None => OutputFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionSettings {
+    pub format: Compression,
+    // Format-specific level, None uses the format's default
+    pub level: Option<i32>,
+    // Worker threads for zstd (0 keeps compression single-threaded)
+    pub threads: u32,
+}
+
+impl CompressionSettings {
+    pub fn new(format: Compression, level: Option<i32>, threads: u32) -> Result<Self> {
+        if let Some(level) = level {
+            let (min, max) = match format {
+                Compression::Gzip | Compression::Xz => (0, 9),
+                Compression::Bzip2 => (1, 9),
+                Compression::Zstd => (1, 22),
+            };
+            if level < min || level > max {
+                return Err(anyhow::anyhow!(
+                    "Compression level {} is out of range for {:?} ({}-{})", level, format, min, max
+                ));
+            }
+        }
+        Ok(CompressionSettings { format, level, threads })
+    }
+}
+
+pub fn parse_compression(name: &str) -> Result<Option<Compression>> {
+    match name {
+        "none" => Ok(None),
+        "gzip" | "gz" => Ok(Some(Compression::Gzip)),
+        "bzip2" | "bz2" => Ok(Some(Compression::Bzip2)),
+        "xz" => Ok(Some(Compression::Xz)),
+        "zstd" | "zst" => Ok(Some(Compression::Zstd)),
+        _ => Err(anyhow::anyhow!("Unknown compression format: {}", name)),
+    }
+}
+
+// Destination for concatenated output, optionally wrapped in a streaming compressor.
+// Compressed streams must be closed with finish() to write their trailers.
+pub enum OutputWriter<W: Write> {
+    Plain(BufWriter<W>),
+    Gzip(flate2::write::GzEncoder<BufWriter<W>>),
+    Bzip2(bzip2::write::BzEncoder<BufWriter<W>>),
+    Xz(xz2::write::XzEncoder<BufWriter<W>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<W>>),
+}
+
+impl OutputWriter<fs::File> {
+    pub fn create(path: &str, compression: Option<CompressionSettings>) -> Result<Self> {
+        let file = fs::File::create(path)
+            .with_context(|| format!("Failed to create output file: {}", path))?;
+        OutputWriter::new(file, compression)
+    }
+
+    // Add to the end of an existing output, starting on a new line when it does
+    // not end with one. Compressed output gets a new stream, which
+    // decompressors read back as the continuation of the previous ones.
+    pub fn append(path: &str, compression: Option<CompressionSettings>) -> Result<Self> {
+        let separate = compression.is_none() && ends_without_newline(Path::new(path))?;
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open output file: {}", path))?;
+        if separate {
+            file.write_all(b"\n")?;
+        }
+        OutputWriter::new(file, compression)
+    }
+}
+
+// Whether a file exists, is not empty and does not end with a newline
+pub fn ends_without_newline(path: &Path) -> Result<bool> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error).with_context(|| format!("Failed to open output file: {}", path.display())),
+    };
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(inner: W, compression: Option<CompressionSettings>) -> Result<Self> {
+        let inner = BufWriter::new(inner);
+        let Some(settings) = compression else {
+            return Ok(OutputWriter::Plain(inner));
+        };
+
+        Ok(match settings.format {
+            Compression::Gzip => {
+                let level = settings.level.map_or(flate2::Compression::default(), |level| flate2::Compression::new(level as u32));
+                OutputWriter::Gzip(flate2::write::GzEncoder::new(inner, level))
+            }
+            Compression::Bzip2 => {
+                let level = settings.level.map_or(bzip2::Compression::default(), |level| bzip2::Compression::new(level as u32));
+                OutputWriter::Bzip2(bzip2::write::BzEncoder::new(inner, level))
+            }
+            Compression::Xz => {
+                OutputWriter::Xz(xz2::write::XzEncoder::new(inner, settings.level.unwrap_or(6) as u32))
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(inner, settings.level.unwrap_or(0))
+                    .context("Failed to initialize zstd compressor")?;
+                if settings.threads > 0 {
+                    encoder.multithread(settings.threads)
+                        .context("Failed to enable multi-threaded zstd compression")?;
+                }
+                OutputWriter::Zstd(encoder)
+            }
+        })
+    }
+
+    // Flush everything, write any compression trailer and hand back the underlying writer
+    pub fn finish(self) -> Result<W> {
+        let inner = match self {
+            OutputWriter::Plain(writer) => writer,
+            OutputWriter::Gzip(encoder) => encoder.finish()?,
+            OutputWriter::Bzip2(encoder) => encoder.finish()?,
+            OutputWriter::Xz(encoder) => encoder.finish()?,
+            OutputWriter::Zstd(encoder) => encoder.finish()?,
+        };
+        inner.into_inner()
+            .map_err(|error| error.into_error())
+            .context("Failed to flush output file")
+    }
+}
+
+impl<W: Write> Write for OutputWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Gzip(encoder) => encoder.write(buf),
+            OutputWriter::Bzip2(encoder) => encoder.write(buf),
+            OutputWriter::Xz(encoder) => encoder.write(buf),
+            OutputWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Gzip(encoder) => encoder.flush(),
+            OutputWriter::Bzip2(encoder) => encoder.flush(),
+            OutputWriter::Xz(encoder) => encoder.flush(),
+            OutputWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn round_trip(format: Compression, level: Option<i32>, threads: u32) -> Result<Vec<u8>> {
+        let settings = CompressionSettings::new(format, level, threads)?;
+        let mut writer = OutputWriter::new(Vec::new(), Some(settings))?;
+        writer.write_all(b"compressed output")?;
+        let compressed = writer.finish()?;
+        assert_ne!(compressed, b"compressed output");
+
+        let mut decoded = Vec::new();
+        archive::decompress_reader(compressed.as_slice(), format)?.read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_compressed_round_trip() -> Result<()> {
+        assert_eq!(round_trip(Compression::Gzip, Some(9), 0)?, b"compressed output");
+        assert_eq!(round_trip(Compression::Bzip2, None, 0)?, b"compressed output");
+        assert_eq!(round_trip(Compression::Xz, Some(1), 0)?, b"compressed output");
+        assert_eq!(round_trip(Compression::Zstd, Some(19), 2)?, b"compressed output");
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_starts_on_a_new_line() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("out.txt");
+        let path_name = path.to_string_lossy();
+        for chunk in ["first", "second\n", "third"] {
+            let mut writer = OutputWriter::append(&path_name, None)?;
+            writer.write_all(chunk.as_bytes())?;
+            writer.finish()?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "first\nsecond\nthird");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_level_range() {
+        assert!(CompressionSettings::new(Compression::Gzip, Some(10), 0).is_err());
+        assert!(CompressionSettings::new(Compression::Zstd, Some(22), 0).is_ok());
+        assert!(parse_compression("lz4").is_err());
+        assert_eq!(parse_compression("none").unwrap(), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::Write;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::output::OutputFormat;
+use crate::archive::input_mtime;
+use crate::timestamp::civil_from_unix;
+use crate::{normalize_line_endings, read_input_file, relative_path, ConcatOptions};
+
+fn zip_timestamp(mtime: u64) -> zip::DateTime {
+    let (year, month, day, hour, minute, second) = civil_from_unix(mtime as i64);
+    // Zip timestamps cannot represent dates before 1980
+    u16::try_from(year)
+        .ok()
+        .and_then(|year| zip::DateTime::from_date_and_time(year, month as u8, day as u8, hour as u8, minute as u8, second as u8).ok())
+        .unwrap_or_default()
+}
+
+// Pack every input into a tar or zip stream under its relative path.
+// Entries hold normalized text (UTF-8, LF) and keep the source mtime, with
+// fixed ownership and permissions so identical inputs give identical archives.
+pub fn write_archive<W: Write>(output: W, files: &[PathBuf], options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<W> {
+    match options.format {
+        OutputFormat::Tar => {
+            let mut builder = tar::Builder::new(output);
+            for file_path in files {
+                let Some(content) = read_input_file(file_path, options, progress)? else {
+                    continue;
+                };
+                let content = normalize_line_endings(&content);
+                let mut header = tar::Header::new_gnu();
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_uid(0);
+                header.set_gid(0);
+                header.set_mtime(input_mtime(file_path)?);
+                builder.append_data(&mut header, relative_path(file_path), content.as_bytes())
+                    .with_context(|| format!("Failed to add file to tar archive: {:?}", file_path))?;
+            }
+            Ok(builder.into_inner()?)
+        }
+        OutputFormat::Zip => {
+            let mut zip = zip::ZipWriter::new_stream(output);
+            for file_path in files {
+                let Some(content) = read_input_file(file_path, options, progress)? else {
+                    continue;
+                };
+                let content = normalize_line_endings(&content);
+                let entry_options = zip::write::SimpleFileOptions::default()
+                    .last_modified_time(zip_timestamp(input_mtime(file_path)?))
+                    .unix_permissions(0o644);
+                zip.start_file(relative_path(file_path), entry_options)
+                    .with_context(|| format!("Failed to add file to zip archive: {:?}", file_path))?;
+                zip.write_all(content.as_bytes())?;
+            }
+            Ok(zip.finish()?.into_inner())
+        }
+        OutputFormat::Text | OutputFormat::Markdown | OutputFormat::Json | OutputFormat::Jsonl => unreachable!("{:?} output is not an archive", options.format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_tar_archive() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("a.txt");
+        let file2 = temp_dir.path().join("b.txt");
+        fs::write(&file1, "first\r\nline\r\n")?;
+        fs::write(&file2, "second")?;
+
+        let options = ConcatOptions { format: OutputFormat::Tar, ..Default::default() };
+        let bytes = write_archive(Vec::new(), &[file1.clone(), file2], &options, None)?;
+
+        let mut tar = tar::Archive::new(bytes.as_slice());
+        let mut entries = Vec::new();
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            entries.push((entry.path()?.to_string_lossy().to_string(), entry.header().mtime()?, content));
+        }
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].0.ends_with("a.txt"));
+        assert_eq!(entries[0].1, input_mtime(&file1)?);
+        assert_eq!(entries[0].2, "first\nline\n");
+        assert_eq!(entries[1].2, "second");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_zip_archive() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let file1 = temp_dir.path().join("a.txt");
+        fs::write(&file1, "zipped\r\n")?;
+
+        let options = ConcatOptions { format: OutputFormat::Zip, ..Default::default() };
+        let bytes = write_archive(Vec::new(), &[file1], &options, None)?;
+
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+        let mut entry = zip.by_index(0)?;
+        assert!(entry.name().ends_with("a.txt"));
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        assert_eq!(content, "zipped\n");
+        Ok(())
+    }
+}
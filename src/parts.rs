@@ -0,0 +1,305 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::output::{CompressionSettings, OutputWriter};
+use crate::{bundle, bundle_record, lines, read_numbered_input, relative_path, ConcatOptions};
+
+// Limits after which the output rolls over into the next numbered part
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SplitSettings {
+    pub max_bytes: Option<u64>,
+    pub max_lines: Option<u64>,
+    pub max_files: Option<usize>,
+    // Only start a new part between input files, never inside one
+    pub whole_files: bool,
+    // Where to write the list of inputs contained in each part
+    pub manifest: Option<PathBuf>,
+}
+
+// Parse a size such as "1500", "64K", "10M" or "1G" (powers of 1024)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1024),
+        Some((index, 'm' | 'M')) => (&value[..index], 1024 * 1024),
+        Some((index, 'g' | 'G')) => (&value[..index], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    match number.trim().parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number * multiplier),
+        _ => Err(format!("invalid size: {}", value)),
+    }
+}
+
+// "out.txt" becomes "out.001.txt", "bundle.txt.zst" becomes "bundle.001.txt.zst"
+pub fn part_path(output_path: &Path, number: usize) -> PathBuf {
+    let file_name = output_path.file_name().unwrap_or_default().to_string_lossy();
+    let part_name = match file_name.split_once('.') {
+        Some((stem, extensions)) if !stem.is_empty() => format!("{}.{:03}.{}", stem, number, extensions),
+        _ => format!("{}.{:03}", file_name, number),
+    };
+    output_path.with_file_name(part_name)
+}
+
+struct Part {
+    path: PathBuf,
+    writer: OutputWriter<fs::File>,
+    bytes: u64,
+    lines: u64,
+    files: usize,
+    inputs: Vec<String>,
+}
+
+struct PartWriter<'a> {
+    output_path: PathBuf,
+    settings: &'a SplitSettings,
+    compression: Option<CompressionSettings>,
+    // Separate files with a newline, as the flat text output does
+    separate_files: bool,
+    current: Part,
+    finished: Vec<(PathBuf, Vec<String>)>,
+}
+
+impl<'a> PartWriter<'a> {
+    fn new(output_path: &Path, settings: &'a SplitSettings, compression: Option<CompressionSettings>, separate_files: bool) -> Result<Self> {
+        Ok(PartWriter {
+            output_path: output_path.to_path_buf(),
+            settings,
+            compression,
+            separate_files,
+            current: Self::open_part(output_path, 1, compression)?,
+            finished: Vec::new(),
+        })
+    }
+
+    fn open_part(output_path: &Path, number: usize, compression: Option<CompressionSettings>) -> Result<Part> {
+        let path = part_path(output_path, number);
+        Ok(Part {
+            writer: OutputWriter::create(&path.to_string_lossy(), compression)?,
+            path,
+            bytes: 0,
+            lines: 0,
+            files: 0,
+            inputs: Vec::new(),
+        })
+    }
+
+    fn roll_over(&mut self) -> Result<()> {
+        let number = self.finished.len() + 2;
+        let next = Self::open_part(&self.output_path, number, self.compression)?;
+        let previous = std::mem::replace(&mut self.current, next);
+        previous.writer.finish()?;
+        self.finished.push((previous.path, previous.inputs));
+        Ok(())
+    }
+
+    fn exceeds(&self, extra_bytes: u64, extra_lines: u64) -> bool {
+        let part = &self.current;
+        self.settings.max_bytes.is_some_and(|max| part.bytes + extra_bytes > max)
+            || self.settings.max_lines.is_some_and(|max| part.lines + extra_lines > max)
+    }
+
+    // A part may hold the tail of a file without counting it in `files`
+    fn has_content(&self) -> bool {
+        self.current.files > 0 || self.current.bytes > 0
+    }
+
+    fn separator(&self) -> &'static str {
+        if self.separate_files && self.has_content() { "\n" } else { "" }
+    }
+
+    fn write(&mut self, separator: &str, data: &[u8], lines: u64) -> Result<()> {
+        self.current.writer.write_all(separator.as_bytes())?;
+        self.current.writer.write_all(data)?;
+        self.current.bytes += separator.len() as u64;
+        self.current.bytes += data.len() as u64;
+        self.current.lines += lines;
+        Ok(())
+    }
+
+    fn record_input(&mut self, name: &str) {
+        if self.current.inputs.last().is_none_or(|last| last != name) {
+            self.current.inputs.push(name.to_string());
+        }
+    }
+
+    fn write_file(&mut self, name: &str, data: &[u8], whole_file: bool) -> Result<()> {
+        if self.settings.max_files.is_some_and(|max| self.current.files >= max) {
+            self.roll_over()?;
+        }
+
+        let lines: Vec<&[u8]> = data.split_inclusive(|&byte| byte == b'\n').collect();
+        if whole_file || lines.is_empty() {
+            let extra_bytes = (self.separator().len() + data.len()) as u64;
+            if self.has_content() && self.exceeds(extra_bytes, lines.len() as u64) {
+                self.roll_over()?;
+            }
+            let separator = self.separator();
+            self.write(separator, data, lines.len() as u64)?;
+            self.record_input(name);
+        } else {
+            // Parts only break at line boundaries; a line longer than the size
+            // limit gets a part of its own
+            for (index, line) in lines.iter().enumerate() {
+                let separator = if index == 0 { self.separator() } else { "" };
+                let extra_bytes = (separator.len() + line.len()) as u64;
+                if self.has_content() && self.exceeds(extra_bytes, 1) {
+                    self.roll_over()?;
+                }
+                let separator = if index == 0 { self.separator() } else { "" };
+                self.write(separator, line, 1)?;
+                self.record_input(name);
+            }
+        }
+
+        self.current.files += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<(PathBuf, Vec<String>)>> {
+        self.current.writer.finish()?;
+        self.finished.push((self.current.path, self.current.inputs));
+        Ok(self.finished)
+    }
+}
+
+// Concatenate the inputs into numbered parts, returning the part paths
+pub fn write_parts(files: &[PathBuf], output_path: &str, options: &ConcatOptions, settings: &SplitSettings, progress: Option<&ProgressBar>) -> Result<Vec<PathBuf>> {
+    let mut writer = PartWriter::new(Path::new(output_path), settings, options.compression, !options.headers)?;
+
+    let mut prefixer = lines::LinePrefixer::new(options.prefix_lines.as_deref(), options.number);
+    let header_names = if options.headers { bundle::entry_names(files)? } else { Vec::new() };
+    for (index, file_path) in files.iter().enumerate() {
+        let name = relative_path(file_path);
+        if options.headers {
+            let Some((header, content)) = bundle_record(file_path, &header_names[index], index + 1, &mut prefixer, options, progress)? else {
+                continue;
+            };
+            // A header record must stay in one part to remain splittable
+            let record = [header.as_bytes(), &content, b"\n"].concat();
+            writer.write_file(&name, &record, true)?;
+            continue;
+        }
+        let Some((content, origins)) = read_numbered_input(file_path, options, progress)? else {
+            continue;
+        };
+        let content = prefixer.apply(file_path, index + 1, &content, &origins);
+        writer.write_file(&name, content.trim_end().as_bytes(), settings.whole_files)?;
+    }
+
+    let parts = writer.finish()?;
+    // Parts left by an earlier run that needed more would pass for part of
+    // this output
+    let mut number = parts.len() + 1;
+    while part_path(Path::new(output_path), number).is_file() {
+        let stale = part_path(Path::new(output_path), number);
+        fs::remove_file(&stale).with_context(|| format!("Failed to remove stale part: {}", stale.display()))?;
+        number += 1;
+    }
+    if let Some(manifest_path) = &settings.manifest {
+        write_manifest(manifest_path, &parts)?;
+    }
+    Ok(parts.into_iter().map(|(path, _)| path).collect())
+}
+
+// One "part<TAB>input" line per input in each part; an input split across
+// parts is listed under each of them
+fn write_manifest(manifest_path: &Path, parts: &[(PathBuf, Vec<String>)]) -> Result<()> {
+    let mut manifest = String::new();
+    for (part, inputs) in parts {
+        for input in inputs {
+            manifest.push_str(&format!("{}\t{}\n", part.display(), input));
+        }
+    }
+    fs::write(manifest_path, manifest)
+        .with_context(|| format!("Failed to write split manifest: {}", manifest_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write_input;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("10m"), Ok(10 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("ten").is_err());
+    }
+
+    #[test]
+    fn test_part_path() {
+        assert_eq!(part_path(Path::new("dir/out.txt"), 1), PathBuf::from("dir/out.001.txt"));
+        assert_eq!(part_path(Path::new("bundle.txt.zst"), 12), PathBuf::from("bundle.012.txt.zst"));
+        assert_eq!(part_path(Path::new("output"), 3), PathBuf::from("output.003"));
+    }
+
+    fn write_inputs(temp_dir: &TempDir) -> Result<Vec<PathBuf>> {
+        [("a.txt", "a1\na2\na3\n"), ("b.txt", "b1\nb2\n"), ("c.txt", "c1\n")]
+            .into_iter()
+            .map(|(name, content)| write_input(temp_dir, name, content))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_by_lines_inside_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+        let output = temp_dir.path().join("out.txt");
+        let settings = SplitSettings { max_lines: Some(2), ..Default::default() };
+
+        let parts = write_parts(&files, output.to_str().unwrap(), &ConcatOptions::default(), &settings, None)?;
+        let contents: Vec<String> = parts.iter().map(fs::read_to_string).collect::<Result<_, _>>()?;
+        assert_eq!(contents, vec!["a1\na2\n", "a3\nb1\n", "b2\nc1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_whole_files_with_manifest() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+        let output = temp_dir.path().join("out.txt");
+        let manifest = temp_dir.path().join("parts.tsv");
+        let settings = SplitSettings {
+            max_lines: Some(4),
+            whole_files: true,
+            manifest: Some(manifest.clone()),
+            ..Default::default()
+        };
+
+        let parts = write_parts(&files, output.to_str().unwrap(), &ConcatOptions::default(), &settings, None)?;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(fs::read_to_string(&parts[0])?, "a1\na2\na3");
+        assert_eq!(fs::read_to_string(&parts[1])?, "b1\nb2\nc1");
+
+        let manifest = fs::read_to_string(manifest)?;
+        let entries: Vec<&str> = manifest.lines().collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].starts_with(&format!("{}\t", parts[0].display())) && entries[0].ends_with("a.txt"));
+        assert!(entries[2].starts_with(&format!("{}\t", parts[1].display())) && entries[2].ends_with("c.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_by_file_count() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+        let output = temp_dir.path().join("out.txt");
+        let settings = SplitSettings { max_files: Some(1), ..Default::default() };
+        assert_eq!(write_parts(&files, output.to_str().unwrap(), &ConcatOptions::default(), &settings, None)?.len(), 3);
+
+        // Re-running with fewer parts removes the ones left over
+        let settings = SplitSettings { max_files: Some(2), ..Default::default() };
+        let parts = write_parts(&files, output.to_str().unwrap(), &ConcatOptions::default(), &settings, None)?;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(fs::read_to_string(&parts[1])?, "c1");
+        assert!(!part_path(&output, 3).exists());
+        Ok(())
+    }
+}
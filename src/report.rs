@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::manifest::OutputRecord;
+use crate::{is_binary, relative_path};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    // Detected as the input is decoded; None for text left out undecoded
+    // (larger than --max-file-size in markdown)
+    pub encoding: Option<String>,
+    // Raw bytes read, after decompression
+    pub bytes_in: u64,
+    // Bytes of decoded and transformed text, None for inputs written as raw
+    // bytes or left out (binary files)
+    pub bytes_out: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Durations {
+    pub total_ms: f64,
+    pub resolution_ms: f64,
+    // Reading, decompressing and transforming the inputs
+    pub reading_ms: f64,
+    // Writing the output, reading excluded
+    pub writing_ms: f64,
+}
+
+pub fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub version: String,
+    // Inputs as given on the command line
+    pub inputs: Vec<String>,
+    // Files the inputs resolved to, in order
+    pub resolved: Vec<String>,
+    // False when --incremental found the output up to date
+    pub updated: bool,
+    pub files: Vec<FileReport>,
+    pub skipped: Vec<SkippedFile>,
+    pub durations: Durations,
+    pub outputs: Vec<OutputRecord>,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    files: BTreeMap<PathBuf, FileReport>,
+    reading: Duration,
+}
+
+// Collects what is read from each input, and how long reading takes, for the
+// run report. Reading an input again replaces its figures but adds its time.
+#[derive(Debug, Clone, Default)]
+pub struct RunRecorder(Arc<Mutex<Recorded>>);
+
+impl RunRecorder {
+    // The encoding of text is recorded once it is decoded, by record_decoded
+    pub fn record_read(&self, file_path: &Path, bytes: &[u8], elapsed: Duration) {
+        let mut recorded = self.0.lock().unwrap();
+        recorded.reading += elapsed;
+        recorded.files.insert(file_path.to_path_buf(), FileReport {
+            path: relative_path(file_path),
+            encoding: is_binary(bytes).then(|| "binary".to_string()),
+            bytes_in: bytes.len() as u64,
+            bytes_out: None,
+        });
+    }
+
+    // Figures of an input decoded as it was read, without transformations
+    pub fn record_streamed(&self, file_path: &Path, encoding: &str, bytes_in: u64, bytes_out: u64, elapsed: Duration) {
+        let mut recorded = self.0.lock().unwrap();
+        recorded.reading += elapsed;
+        recorded.files.insert(file_path.to_path_buf(), FileReport {
+            path: relative_path(file_path),
+            encoding: Some(encoding.to_string()),
+            bytes_in,
+            bytes_out: Some(bytes_out),
+        });
+    }
+
+    // Binary inputs keep their "binary" encoding when the output decodes them
+    pub fn record_decoded(&self, file_path: &Path, encoding: &str) {
+        if let Some(file) = self.0.lock().unwrap().files.get_mut(file_path) {
+            file.encoding.get_or_insert_with(|| encoding.to_string());
+        }
+    }
+
+    pub fn record_transformed(&self, file_path: &Path, length: usize, elapsed: Duration) {
+        let mut recorded = self.0.lock().unwrap();
+        recorded.reading += elapsed;
+        if let Some(file) = recorded.files.get_mut(file_path) {
+            file.bytes_out = Some(length as u64);
+        }
+    }
+
+    pub fn reading_time(&self) -> Duration {
+        self.0.lock().unwrap().reading
+    }
+
+    // Forget the time spent reading so far, so that passes over the inputs
+    // before the output is written do not count towards reading_ms
+    pub fn reset_reading(&self) {
+        self.0.lock().unwrap().reading = Duration::ZERO;
+    }
+
+    // Figures of the given inputs, in their order
+    pub fn files(&self, files: &[PathBuf]) -> Vec<FileReport> {
+        let recorded = self.0.lock().unwrap();
+        files.iter().filter_map(|file_path| recorded.files.get(file_path).cloned()).collect()
+    }
+}
+
+// Write the report to a file, or to stdout for "-"
+pub fn write_report(destination: &str, report: &RunReport) -> Result<()> {
+    let json = serde_json::to_string_pretty(report)? + "\n";
+    if destination == "-" {
+        print!("{}", json);
+        return Ok(());
+    }
+    fs::write(destination, json).with_context(|| format!("Failed to write report: {}", destination))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recorder_tracks_reads_and_transforms() {
+        let recorder = RunRecorder::default();
+        let text = PathBuf::from("a.txt");
+        let image = PathBuf::from("b.png");
+        recorder.record_read(&text, b"hello\n", Duration::from_millis(2));
+        recorder.record_decoded(&text, "UTF-8");
+        recorder.record_transformed(&text, 5, Duration::from_millis(1));
+        recorder.record_read(&image, &[0x89, 0x50, 0x00, 0x01], Duration::from_millis(1));
+        recorder.record_decoded(&image, "UTF-8");
+
+        assert_eq!(recorder.reading_time(), Duration::from_millis(4));
+        assert_eq!(
+            recorder.files(&[image.clone(), text.clone(), PathBuf::from("missing.txt")]),
+            vec![
+                FileReport { path: "b.png".to_string(), encoding: Some("binary".to_string()), bytes_in: 4, bytes_out: None },
+                FileReport { path: "a.txt".to_string(), encoding: Some("UTF-8".to_string()), bytes_in: 6, bytes_out: Some(5) },
+            ]
+        );
+
+        // Resetting the time keeps the figures of each input
+        recorder.reset_reading();
+        recorder.record_read(&text, b"hello\n", Duration::from_millis(3));
+        assert_eq!(recorder.reading_time(), Duration::from_millis(3));
+        assert_eq!(recorder.files(&[image, text]).len(), 2);
+    }
+
+    #[test]
+    fn test_milliseconds() {
+        assert_eq!(milliseconds(Duration::from_micros(1500)), 1.5);
+        assert_eq!(milliseconds(Duration::from_secs(2)), 2000.0);
+    }
+}
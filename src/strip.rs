@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use crate::lines::Origins;
+
+// Comment and string syntax of a source language
+#[derive(Debug, PartialEq)]
+pub struct CommentSyntax {
+    line: &'static [&'static str],
+    block: &'static [(&'static str, &'static str)],
+    // Quote characters opening string literals whose content is left untouched
+    quotes: &'static [char],
+    // ' opens a char literal only when it looks like one ('a', '\n'), as Rust
+    // also uses it for lifetimes
+    char_literals: bool,
+}
+
+const C_LIKE: CommentSyntax = CommentSyntax { line: &["//"], block: &[("/*", "*/")], quotes: &['"', '\''], char_literals: false };
+const JS_LIKE: CommentSyntax = CommentSyntax { line: &["//"], block: &[("/*", "*/")], quotes: &['"', '\'', '`'], char_literals: false };
+const RUST: CommentSyntax = CommentSyntax { line: &["//"], block: &[("/*", "*/")], quotes: &['"'], char_literals: true };
+const CSS: CommentSyntax = CommentSyntax { line: &[], block: &[("/*", "*/")], quotes: &['"', '\''], char_literals: false };
+const HASH: CommentSyntax = CommentSyntax { line: &["#"], block: &[], quotes: &['"', '\''], char_literals: false };
+const SQL: CommentSyntax = CommentSyntax { line: &["--"], block: &[("/*", "*/")], quotes: &['"', '\''], char_literals: false };
+const LUA: CommentSyntax = CommentSyntax { line: &["--"], block: &[("--[[", "]]")], quotes: &['"', '\''], char_literals: false };
+const HASKELL: CommentSyntax = CommentSyntax { line: &["--"], block: &[("{-", "-}")], quotes: &['"'], char_literals: false };
+// Quotes in markup are prose, not strings
+const MARKUP: CommentSyntax = CommentSyntax { line: &[], block: &[("<!--", "-->")], quotes: &[], char_literals: false };
+
+// Comment syntax for a file, from its name or extension
+pub fn comment_syntax(path: &Path) -> Option<&'static CommentSyntax> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    if matches!(file_name.as_str(), "dockerfile" | "makefile" | "gnumakefile" | "cmakelists.txt" | ".gitignore" | ".env") {
+        return Some(&HASH);
+    }
+
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "rs" => Some(&RUST),
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" | "cs" | "java" | "kt" | "kts" | "scala" | "swift" | "go" | "dart" | "php" | "proto" => Some(&C_LIKE),
+        "js" | "mjs" | "cjs" | "jsx" | "ts" | "mts" | "cts" | "tsx" => Some(&JS_LIKE),
+        "css" | "scss" | "less" => Some(&CSS),
+        "py" | "pyi" | "sh" | "bash" | "zsh" | "rb" | "pl" | "pm" | "r" | "yaml" | "yml" | "toml" | "ini" | "cfg" | "conf" | "ps1" | "ex" | "exs" | "graphql" | "gql" => Some(&HASH),
+        "sql" => Some(&SQL),
+        "lua" => Some(&LUA),
+        "hs" => Some(&HASKELL),
+        "html" | "htm" | "xml" | "svg" | "vue" | "md" | "markdown" => Some(&MARKUP),
+        _ => None,
+    }
+}
+
+// Length of the string or char literal starting at the beginning of text, or
+// None when the quote does not open one
+fn literal_length(text: &str, quote: char, syntax: &CommentSyntax) -> Option<usize> {
+    if quote == '\'' && syntax.char_literals {
+        let mut chars = text.char_indices().skip(1);
+        let (_, first) = chars.next()?;
+        let (index, next) = chars.next()?;
+        return match (first, next) {
+            ('\\', _) => text[index..].find('\'').map(|end| index + end + 1),
+            (_, '\'') => Some(index + 1),
+            _ => None,
+        };
+    }
+
+    // Python-style triple-quoted strings
+    let triple = quote.to_string().repeat(3);
+    if text.starts_with(&triple) {
+        return Some(text[3..].find(&triple).map_or(text.len(), |end| end + 6));
+    }
+
+    let mut escaped = false;
+    for (index, character) in text.char_indices().skip(1) {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if character == quote => return Some(index + 1),
+            // Unterminated strings end with the line, except template literals
+            '\n' if quote != '`' => return Some(index),
+            _ => {}
+        }
+    }
+    Some(text.len())
+}
+
+// A ' right after a letter or digit is an apostrophe (it's) or a digit
+// separator (1'000), unless the word before it is a string prefix such as
+// Python's r or b
+fn is_apostrophe(before: &str, quote: char) -> bool {
+    let word = &before[before.trim_end_matches(char::is_alphanumeric).len()..];
+    quote == '\''
+        && !word.is_empty()
+        && !matches!(word.to_lowercase().as_str(), "r" | "b" | "f" | "u" | "rb" | "br" | "fr" | "rf")
+}
+
+// Remove comments outside string literals. Lines left blank by a removed
+// comment are dropped; other blank lines are kept. A line joined by a block
+// comment keeps the origin of the line it starts on.
+pub fn strip_comments(content: &str, origins: &[Option<usize>], syntax: &CommentSyntax) -> (String, Origins) {
+    let mut stripped = String::with_capacity(content.len());
+    let mut stripped_origins = Origins::new();
+    let mut line_start = 0;
+    let mut removed_comment = false;
+    let mut index = 0;
+    // Zero-based line of content at index, and the one the output line being
+    // built started on
+    let mut input_line = 0;
+    let mut line_origin: Option<usize> = None;
+    let origin = |line: usize| origins.get(line).copied().flatten();
+
+    // A shebang is read by the system, so it is kept
+    if syntax.line.contains(&"#") && content.starts_with("#!") {
+        index = content.find('\n').unwrap_or(content.len());
+        stripped.push_str(&content[..index]);
+        line_origin = Some(0);
+    }
+
+    while index < content.len() {
+        let rest = &content[index..];
+        if let Some((start, end)) = syntax.block.iter().find(|(start, _)| rest.starts_with(start)) {
+            let length = rest[start.len()..].find(end).map_or(rest.len(), |position| start.len() + position + end.len());
+            input_line += rest[..length].matches('\n').count();
+            index += length;
+            removed_comment = true;
+            continue;
+        }
+        // A # comment must start a word, so $# or a#b are left alone
+        if syntax.line.iter().any(|marker| {
+            rest.starts_with(marker)
+                && (*marker != "#" || stripped[line_start..].chars().last().is_none_or(char::is_whitespace))
+        }) {
+            index += rest.find('\n').unwrap_or(rest.len());
+            removed_comment = true;
+            continue;
+        }
+
+        let character = rest.chars().next().unwrap();
+        if syntax.quotes.contains(&character)
+            && !is_apostrophe(&content[..index], character)
+            && let Some(length) = literal_length(rest, character, syntax)
+        {
+            let literal = &rest[..length];
+            line_origin.get_or_insert(input_line);
+            stripped.push_str(literal);
+            for _ in literal.matches('\n') {
+                stripped_origins.push(line_origin.and_then(origin));
+                input_line += 1;
+                line_origin = Some(input_line);
+            }
+            index += length;
+            continue;
+        }
+
+        if character == '\n' {
+            if removed_comment {
+                let kept = stripped[line_start..].trim_end().len();
+                stripped.truncate(line_start + kept);
+            }
+            if !(removed_comment && stripped.len() == line_start) {
+                stripped.push('\n');
+                stripped_origins.push(origin(line_origin.unwrap_or(input_line)));
+            }
+            line_start = stripped.len();
+            removed_comment = false;
+            input_line += 1;
+            line_origin = None;
+        } else {
+            line_origin.get_or_insert(input_line);
+            stripped.push(character);
+        }
+        index += character.len_utf8();
+    }
+
+    if removed_comment {
+        let kept = stripped[line_start..].trim_end().len();
+        stripped.truncate(line_start + kept);
+    }
+    if !stripped.is_empty() && !stripped.ends_with('\n') {
+        stripped_origins.push(origin(line_origin.unwrap_or(input_line)));
+    }
+    (stripped, stripped_origins)
+}
+
+// Collapse runs of blank lines into a single one, as `cat -s` does
+pub fn squeeze_blank(content: &str, origins: &[Option<usize>]) -> (String, Origins) {
+    let mut squeezed = String::with_capacity(content.len());
+    let mut squeezed_origins = Origins::new();
+    let mut previous_blank = false;
+    for (line, origin) in content.split_inclusive('\n').zip(origins) {
+        let blank = line.trim().is_empty();
+        if !(blank && previous_blank) {
+            squeezed.push_str(line);
+            squeezed_origins.push(*origin);
+        }
+        previous_blank = blank;
+    }
+    (squeezed, squeezed_origins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::source_origins;
+
+    fn strip(name: &str, content: &str) -> String {
+        strip_comments(content, &source_origins(content), comment_syntax(Path::new(name)).unwrap()).0
+    }
+
+    #[test]
+    fn test_strip_c_like_comments() {
+        let source = "// header\nfn main() { // entry\n    let url = \"http://example.com\"; /* inline */ let c = '\"';\n    /* multi\n       line */\n    f::<'a>(\"/* kept */\");\n}\n";
+        assert_eq!(
+            strip("main.rs", source),
+            "fn main() {\n    let url = \"http://example.com\";  let c = '\"';\n    f::<'a>(\"/* kept */\");\n}\n"
+        );
+        let (_, origins) = strip_comments(source, &source_origins(source), comment_syntax(Path::new("main.rs")).unwrap());
+        assert_eq!(origins, vec![Some(2), Some(3), Some(6), Some(7)]);
+        assert_eq!(strip("app.js", "const s = `// not a comment`; // comment\n"), "const s = `// not a comment`;\n");
+    }
+
+    #[test]
+    fn test_strip_other_syntaxes() {
+        assert_eq!(strip("run.sh", "# setup\necho \"#1\" $# # done\n"), "echo \"#1\" $#\n");
+        assert_eq!(strip("tool.py", "x = 1  # one\ndoc = \"\"\"# kept\n\"\"\"\n"), "x = 1\ndoc = \"\"\"# kept\n\"\"\"\n");
+        assert_eq!(strip("schema.sql", "SELECT '--x' -- comment\nFROM t;\n"), "SELECT '--x'\nFROM t;\n");
+        assert_eq!(strip("page.html", "<p>it's</p><!-- note -->\n<!--\nblock\n-->\n<b>x</b>\n"), "<p>it's</p>\n<b>x</b>\n");
+        assert_eq!(comment_syntax(Path::new("notes.txt")), None);
+    }
+
+    #[test]
+    fn test_strip_keeps_shebangs_and_apostrophes() {
+        assert_eq!(strip("run.sh", "#!/bin/sh\n# setup\necho it's # note\n"), "#!/bin/sh\necho it's\n");
+        assert_eq!(strip("config.yml", "title: it's done # note\nname: 'a # b'\n"), "title: it's done\nname: 'a # b'\n");
+        assert_eq!(strip("tool.py", "p = r'#x'  # raw\n"), "p = r'#x'\n");
+        assert_eq!(strip("main.cpp", "int n = 1'000; // '\n"), "int n = 1'000;\n");
+    }
+
+    #[test]
+    fn test_squeeze_blank() {
+        let content = "a\n\n\n  \nb\n\nc\n";
+        let (squeezed, origins) = squeeze_blank(content, &source_origins(content));
+        assert_eq!(squeezed, "a\n\nb\n\nc\n");
+        assert_eq!(origins, vec![Some(1), Some(2), Some(5), Some(6), Some(7)]);
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::{read_input_file, relative_path, ConcatOptions};
+
+// What to do when a file's header row differs from the first file's
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HeaderMismatch {
+    #[default]
+    Error,
+    // Match columns by name; columns a file lacks are left empty
+    Align,
+}
+
+impl HeaderMismatch {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "error" => Ok(HeaderMismatch::Error),
+            "align" => Ok(HeaderMismatch::Align),
+            _ => Err(anyhow::anyhow!("Unknown CSV header mismatch policy: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub quote: u8,
+}
+
+const DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+// Occurrences of byte outside double-quoted sections of a line
+fn count_unquoted(line: &str, byte: u8, quote: u8) -> usize {
+    let mut quoted = false;
+    let mut count = 0;
+    for &character in line.as_bytes() {
+        if character == quote {
+            quoted = !quoted;
+        } else if character == byte && !quoted {
+            count += 1;
+        }
+    }
+    count
+}
+
+// Guess the delimiter and quote character from the first lines: the delimiter
+// that splits every sampled line into the same number of fields wins, then the
+// one that appears most often in the header
+pub fn sniff_dialect(content: &str) -> Dialect {
+    let sample: Vec<&str> = content.lines().filter(|line| !line.trim().is_empty()).take(10).collect();
+
+    // A field opening with a single quote more often than a double quote
+    let opening = |quote: u8| -> usize {
+        sample.iter().map(|line| {
+            let bytes = line.as_bytes();
+            (0..bytes.len())
+                .filter(|&index| bytes[index] == quote && (index == 0 || DELIMITERS.contains(&bytes[index - 1])))
+                .count()
+        }).sum()
+    };
+    let quote = if opening(b'\'') > opening(b'"') { b'\'' } else { b'"' };
+
+    let Some(header) = sample.first() else {
+        return Dialect { delimiter: b',', quote };
+    };
+    let score = |delimiter: u8| -> (bool, usize) {
+        let counts: Vec<usize> = sample.iter().map(|line| count_unquoted(line, delimiter, quote)).collect();
+        let consistent = counts[0] > 0 && counts.iter().all(|&count| count == counts[0]);
+        (consistent, count_unquoted(header, delimiter, quote))
+    };
+    let delimiter = DELIMITERS.iter()
+        .copied()
+        .filter(|&delimiter| score(delimiter).1 > 0)
+        .max_by_key(|&delimiter| score(delimiter))
+        .unwrap_or(b',');
+    Dialect { delimiter, quote }
+}
+
+fn read_records(file_path: &Path, content: &str) -> Result<(Vec<String>, Vec<csv::StringRecord>)> {
+    let dialect = sniff_dialect(content);
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(dialect.delimiter)
+        .quote(dialect.quote)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+
+    let header = reader.headers()
+        .with_context(|| format!("Failed to read CSV header of: {}", relative_path(file_path)))?
+        .iter()
+        .map(|name| name.trim().to_string())
+        .collect();
+    let records = reader.records()
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse CSV file: {}", relative_path(file_path)))?;
+    Ok((header, records))
+}
+
+// Read an input's CSV text; empty inputs have no header to compare and nothing
+// to add, so like inputs skipped by --keep-going they give None
+fn read_table(file_path: &Path, options: &ConcatOptions, progress: Option<&ProgressBar>) -> Result<Option<String>> {
+    Ok(read_input_file(file_path, options, progress)?.filter(|content| !content.trim().is_empty()))
+}
+
+// Concatenate CSV/TSV inputs under a single header row. The output uses the
+// dialect of the first file; with HeaderMismatch::Align its header is the union
+// of all headers, in order of first appearance.
+pub fn write_csv<W: Write>(output: W, files: &[PathBuf], options: &ConcatOptions, mismatch: HeaderMismatch, progress: Option<&ProgressBar>) -> Result<W> {
+    // The first input that can be read sets the dialect and the header
+    let mut remaining = files.iter();
+    let (first, first_content) = loop {
+        let Some(file_path) = remaining.next() else {
+            return Ok(output);
+        };
+        if let Some(content) = read_table(file_path, options, progress)? {
+            break (file_path, content);
+        }
+    };
+    let dialect = sniff_dialect(&first_content);
+    let (first_header, first_records) = read_records(first, &first_content)?;
+    let mut columns = first_header.clone();
+
+    // Headers are known before any row is written, so rows never need
+    // rewriting: with HeaderMismatch::Align every input is parsed up front and
+    // its rows kept rather than read again
+    let mut aligned = Vec::new();
+    if mismatch == HeaderMismatch::Align {
+        for file_path in remaining.by_ref() {
+            let Some(content) = read_table(file_path, options, progress)? else {
+                continue;
+            };
+            let (header, records) = read_records(file_path, &content)?;
+            for name in &header {
+                if !columns.contains(name) {
+                    columns.push(name.clone());
+                }
+            }
+            aligned.push((header, records));
+        }
+    }
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(dialect.delimiter)
+        .quote(dialect.quote)
+        .flexible(true)
+        .from_writer(output);
+    writer.write_record(&columns)?;
+    write_rows(&mut writer, &columns, &first_header, &first_records)?;
+    for (header, records) in &aligned {
+        write_rows(&mut writer, &columns, header, records)?;
+    }
+
+    for file_path in remaining {
+        let Some(content) = read_table(file_path, options, progress)? else {
+            continue;
+        };
+        let (header, records) = read_records(file_path, &content)?;
+        if header != columns && mismatch == HeaderMismatch::Error {
+            return Err(anyhow::anyhow!(
+                "CSV header of {} ({}) differs from {} ({}); use --csv-mismatch align to match columns by name",
+                relative_path(file_path),
+                header.join(", "),
+                relative_path(first),
+                columns.join(", ")
+            ));
+        }
+        write_rows(&mut writer, &columns, &header, &records)?;
+    }
+
+    writer.into_inner().map_err(|error| anyhow::anyhow!("Failed to write CSV output: {}", error.error()))
+}
+
+// Write rows read under header in the order of columns, leaving missing
+// columns empty
+fn write_rows<W: Write>(writer: &mut csv::Writer<W>, columns: &[String], header: &[String], records: &[csv::StringRecord]) -> Result<()> {
+    if header == columns {
+        for record in records {
+            writer.write_record(record)?;
+        }
+        return Ok(());
+    }
+    let positions: Vec<Option<usize>> = columns.iter()
+        .map(|name| header.iter().position(|column| column == name))
+        .collect();
+    for record in records {
+        writer.write_record(positions.iter().map(|position| position.and_then(|index| record.get(index)).unwrap_or("")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::write_input;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sniff_dialect() {
+        assert_eq!(sniff_dialect("a,b,c\n1,2,3\n"), Dialect { delimiter: b',', quote: b'"' });
+        assert_eq!(sniff_dialect("a\tb\n\"x,y\"\t2\n"), Dialect { delimiter: b'\t', quote: b'"' });
+        assert_eq!(sniff_dialect("name;price\n'a;b';1,5\n"), Dialect { delimiter: b';', quote: b'\'' });
+    }
+
+    #[test]
+    fn test_csv_drops_repeated_headers() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![
+            write_input(&temp_dir, "day1.csv", "id,note\n1,\"hello, world\"\n")?,
+            write_input(&temp_dir, "day2.csv", "id,note\r\n2,plain\r\n")?,
+        ];
+        let output = write_csv(Vec::new(), &files, &ConcatOptions::default(), HeaderMismatch::Error, None)?;
+        assert_eq!(String::from_utf8(output)?, "id,note\n1,\"hello, world\"\n2,plain\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_skips_empty_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![
+            write_input(&temp_dir, "empty.csv", "")?,
+            write_input(&temp_dir, "day1.csv", "id,note\n1,one\n")?,
+            write_input(&temp_dir, "blank.csv", "\n")?,
+            write_input(&temp_dir, "day2.csv", "id,note\n2,two\n")?,
+        ];
+        let output = write_csv(Vec::new(), &files, &ConcatOptions::default(), HeaderMismatch::Error, None)?;
+        assert_eq!(String::from_utf8(output)?, "id,note\n1,one\n2,two\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_header_mismatch() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = vec![
+            write_input(&temp_dir, "a.tsv", "id\tname\n1\tann\n")?,
+            write_input(&temp_dir, "b.tsv", "name\temail\nbob\tbob@example.com\n")?,
+        ];
+
+        let error = write_csv(Vec::new(), &files, &ConcatOptions::default(), HeaderMismatch::Error, None).unwrap_err();
+        assert!(error.to_string().contains("b.tsv"));
+
+        let output = write_csv(Vec::new(), &files, &ConcatOptions::default(), HeaderMismatch::Align, None)?;
+        assert_eq!(String::from_utf8(output)?, "id\tname\temail\n1\tann\t\n\tbob\tbob@example.com\n");
+        Ok(())
+    }
+}
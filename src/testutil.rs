@@ -0,0 +1,11 @@
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use tempfile::TempDir;
+
+// Write an input named name into the test directory, returning its path
+pub fn write_input(temp_dir: &TempDir, name: &str, content: impl AsRef<[u8]>) -> Result<PathBuf> {
+    let path = temp_dir.path().join(name);
+    fs::write(&path, content)?;
+    Ok(path)
+}
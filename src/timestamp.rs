@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+// Times outside chrono's range (some 262,000 years around the epoch) read as
+// the epoch
+fn utc_from_unix(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs, 0).unwrap_or_default()
+}
+
+// Convert seconds since the Unix epoch to a UTC (year, month, day, hour, minute, second)
+pub fn civil_from_unix(secs: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let datetime = utc_from_unix(secs);
+    (datetime.year() as i64, datetime.month(), datetime.day(), datetime.hour(), datetime.minute(), datetime.second())
+}
+
+// Seconds since the Unix epoch, negative for earlier times
+pub fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs() as i64),
+    }
+}
+
+// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp
+pub fn format_rfc3339(secs: i64) -> String {
+    utc_from_unix(secs).format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_from_unix() {
+        assert_eq!(civil_from_unix(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil_from_unix(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil_from_unix(1_700_000_000), (2023, 11, 14, 22, 13, 20));
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(format_rfc3339(-1), "1969-12-31T23:59:59Z");
+    }
+}
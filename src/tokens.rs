@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use anyhow::Result;
+use tiktoken_rs::CoreBPE;
+
+use crate::output::OutputFormat;
+use crate::{archive, decode_input, markdown, read_input_bytes, relative_path, transform_content, ConcatOptions};
+
+// Offline BPE tokenizers; the tables are bundled with the binary
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Tokenizer {
+    // GPT-4 / GPT-3.5 family
+    Cl100k,
+    // GPT-4o family
+    #[default]
+    O200k,
+}
+
+impl Tokenizer {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "cl100k" | "cl100k_base" => Ok(Tokenizer::Cl100k),
+            "o200k" | "o200k_base" => Ok(Tokenizer::O200k),
+            _ => Err(anyhow::anyhow!("Unknown tokenizer: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tokenizer::Cl100k => "cl100k_base",
+            Tokenizer::O200k => "o200k_base",
+        }
+    }
+
+    fn bpe(self) -> &'static CoreBPE {
+        match self {
+            Tokenizer::Cl100k => tiktoken_rs::cl100k_base_singleton(),
+            Tokenizer::O200k => tiktoken_rs::o200k_base_singleton(),
+        }
+    }
+
+    pub fn count(self, text: &str) -> usize {
+        self.bpe().encode_ordinary(text).len()
+    }
+
+    // Keep at most max_tokens tokens of text
+    pub fn truncate(self, text: &str, max_tokens: usize) -> String {
+        let tokens = self.bpe().encode_ordinary(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+        // A cut can land inside a multi-byte character; back off until it decodes
+        let mut end = max_tokens;
+        while end > 0 {
+            if let Ok(truncated) = self.bpe().decode(tokens[..end].to_vec()) {
+                return truncated;
+            }
+            end -= 1;
+        }
+        String::new()
+    }
+}
+
+// What to give up first when the inputs exceed --max-tokens
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TokenPolicy {
+    // Drop the files with the most tokens
+    #[default]
+    Largest,
+    // Drop the least recently modified files
+    Oldest,
+    // Drop files from the end of the ordered list
+    Last,
+    // Keep files in order and cut the one that crosses the budget
+    Truncate,
+}
+
+impl TokenPolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "largest" => Ok(TokenPolicy::Largest),
+            "oldest" => Ok(TokenPolicy::Oldest),
+            "last" => Ok(TokenPolicy::Last),
+            "truncate" => Ok(TokenPolicy::Truncate),
+            _ => Err(anyhow::anyhow!("Unknown token policy: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TokenPlan {
+    // Token count of every input, in input order
+    pub counts: Vec<(PathBuf, usize)>,
+    // Inputs to write, in input order
+    pub kept: Vec<PathBuf>,
+    pub dropped: Vec<PathBuf>,
+    // Inputs cut down to the given number of tokens
+    pub truncated: BTreeMap<PathBuf, usize>,
+}
+
+impl TokenPlan {
+    // Tokens of an input as written out, or None if it was dropped
+    pub fn written_tokens(&self, file_path: &PathBuf, count: usize) -> Option<usize> {
+        if self.dropped.contains(file_path) {
+            None
+        } else {
+            Some(self.truncated.get(file_path).copied().unwrap_or(count))
+        }
+    }
+
+    pub fn total_written(&self) -> usize {
+        self.counts.iter()
+            .filter_map(|(path, count)| self.written_tokens(path, *count))
+            .sum()
+    }
+}
+
+// Count the tokens of every input and decide which to keep, drop or truncate
+// to fit max_tokens. The budget covers file contents, not format overhead such
+// as markdown headings.
+pub fn plan_budget(files: &[PathBuf], options: &ConcatOptions, max_tokens: Option<usize>, policy: TokenPolicy) -> Result<TokenPlan> {
+    let mut plan = TokenPlan::default();
+    for file_path in files {
+        let Some(bytes) = read_input_bytes(file_path, options, None)? else {
+            continue;
+        };
+        // Inputs markdown leaves out take no room in the budget
+        let count = if options.format == OutputFormat::Markdown && markdown::omission(&bytes, options).is_some() {
+            0
+        } else {
+            transform_content(file_path, decode_input(file_path, &bytes, options).0, options).map_or(0, |content| options.tokenizer.count(&content))
+        };
+        plan.counts.push((file_path.clone(), count));
+    }
+
+    let total: usize = plan.counts.iter().map(|(_, count)| count).sum();
+    let Some(max_tokens) = max_tokens.filter(|&max_tokens| total > max_tokens) else {
+        plan.kept = plan.counts.iter().map(|(file_path, _)| file_path.clone()).collect();
+        return Ok(plan);
+    };
+
+    match policy {
+        TokenPolicy::Truncate => {
+            let mut remaining = max_tokens;
+            for (file_path, count) in &plan.counts {
+                if *count <= remaining {
+                    remaining -= count;
+                } else if remaining > 0 {
+                    plan.truncated.insert(file_path.clone(), remaining);
+                    remaining = 0;
+                } else {
+                    plan.dropped.push(file_path.clone());
+                }
+            }
+        }
+        TokenPolicy::Largest | TokenPolicy::Oldest | TokenPolicy::Last => {
+            let mut candidates: Vec<&(PathBuf, usize)> = plan.counts.iter().collect();
+            match policy {
+                TokenPolicy::Largest => candidates.sort_by_key(|(_, count)| std::cmp::Reverse(*count)),
+                TokenPolicy::Oldest => {
+                    let modified = |path: &PathBuf| archive::input_metadata(path)
+                        .and_then(|metadata| Ok(metadata.modified()?))
+                        .unwrap_or(SystemTime::UNIX_EPOCH);
+                    candidates.sort_by_key(|(path, _)| modified(path));
+                }
+                _ => candidates.reverse(),
+            }
+
+            let mut remaining_total = total;
+            for (file_path, count) in candidates {
+                if remaining_total <= max_tokens {
+                    break;
+                }
+                remaining_total -= count;
+                plan.dropped.push(file_path.clone());
+            }
+        }
+    }
+
+    plan.kept = plan.counts.iter()
+        .map(|(file_path, _)| file_path)
+        .filter(|path| !plan.dropped.contains(path))
+        .cloned()
+        .collect();
+    Ok(plan)
+}
+
+pub fn print_token_report(plan: &TokenPlan, tokenizer: Tokenizer) {
+    status!("Token counts ({}):", tokenizer.name());
+    for (file_path, count) in &plan.counts {
+        match plan.written_tokens(file_path, *count) {
+            None => status!("{:>10}  {} (dropped)", count, relative_path(file_path)),
+            Some(written) if written < *count => {
+                status!("{:>10}  {} (truncated from {})", written, relative_path(file_path), count)
+            }
+            Some(written) => status!("{:>10}  {}", written, relative_path(file_path)),
+        }
+    }
+    status!("{:>10}  total", plan.total_written());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testutil::write_input;
+    use tempfile::TempDir;
+
+    fn write_inputs(temp_dir: &TempDir) -> Result<Vec<PathBuf>> {
+        [("a.txt", 10), ("b.txt", 30), ("c.txt", 20)]
+            .into_iter()
+            .map(|(name, words)| write_input(temp_dir, name, vec!["hello"; words].join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn test_count_and_truncate() {
+        let tokenizer = Tokenizer::Cl100k;
+        assert_eq!(tokenizer.count("hello world"), 2);
+        assert_eq!(tokenizer.truncate("hello world again", 2), "hello world");
+        assert_eq!(tokenizer.truncate("short", 10), "short");
+    }
+
+    #[test]
+    fn test_budget_drops_largest_first() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+
+        let plan = plan_budget(&files, &ConcatOptions::default(), Some(35), TokenPolicy::Largest)?;
+        assert_eq!(plan.dropped, vec![files[1].clone()]);
+        assert_eq!(plan.kept, vec![files[0].clone(), files[2].clone()]);
+        assert_eq!(plan.total_written(), 30);
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget_truncates_in_order() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+
+        let plan = plan_budget(&files, &ConcatOptions::default(), Some(25), TokenPolicy::Truncate)?;
+        assert_eq!(plan.kept, vec![files[0].clone(), files[1].clone()]);
+        assert_eq!(plan.truncated.get(&files[1]), Some(&15));
+        assert_eq!(plan.dropped, vec![files[2].clone()]);
+        assert_eq!(plan.total_written(), 25);
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget_drops_from_the_end() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let files = write_inputs(&temp_dir)?;
+
+        let plan = plan_budget(&files, &ConcatOptions::default(), Some(40), TokenPolicy::Last)?;
+        assert_eq!(plan.kept, vec![files[0].clone(), files[1].clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget_skips_inputs_omitted_from_markdown() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut files = write_inputs(&temp_dir)?;
+        let binary = temp_dir.path().join("blob.bin");
+        fs::write(&binary, [0x00, 0xFF, 0x10, b'h', b'e', b'l', b'l', b'o'])?;
+        files.push(binary);
+
+        let options = ConcatOptions { format: OutputFormat::Markdown, ..Default::default() };
+        let plan = plan_budget(&files, &options, Some(60), TokenPolicy::Last)?;
+        assert_eq!(plan.counts[3].1, 0);
+        assert!(plan.dropped.is_empty());
+        Ok(())
+    }
+}
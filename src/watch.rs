@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{archive, relative_path};
+
+// Directory to watch for an input, and whether to watch below it. Files are
+// watched through their directory, so editors that save by replacing the file
+// and newly created files matching a wildcard are both seen.
+pub fn watch_root(input: &str, recursive: bool) -> (PathBuf, bool) {
+    if let Some((archive_path, _)) = archive::split_member_path(input) {
+        return (parent_directory(&archive_path), false);
+    }
+
+    let path = Path::new(input);
+    let components: Vec<Component> = path.components().collect();
+    if let Some(wildcard) = components.iter().position(|component| {
+        component.as_os_str().to_string_lossy().contains(['*', '?', '['])
+    }) {
+        let base: PathBuf = components[..wildcard].iter().collect();
+        let base = if base.as_os_str().is_empty() { PathBuf::from(".") } else { base };
+        // A wildcard above the file name matches in subdirectories
+        return (base, recursive || wildcard + 1 < components.len() || input.contains("**"));
+    }
+    if path.is_dir() {
+        (path.to_path_buf(), recursive)
+    } else {
+        (parent_directory(path), false)
+    }
+}
+
+fn parent_directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::env::current_dir().map(|directory| directory.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Add the paths of a change event, leaving out reads and the outputs we write
+fn collect_changes(event: notify::Result<notify::Event>, ignored: &[PathBuf], changed: &mut BTreeSet<PathBuf>) {
+    match event {
+        Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+        Ok(event) => changed.extend(event.paths.into_iter().filter(|path| !ignored.contains(path))),
+        Err(error) => eprintln!("Warning: Watch error: {}", error),
+    }
+}
+
+// Leave the outputs we write out of the resolved inputs, so that writing one an
+// input pattern matches does not count as a change of inputs
+fn without_outputs(files: Vec<PathBuf>, ignored: &[PathBuf]) -> Vec<PathBuf> {
+    files.into_iter().filter(|path| !ignored.contains(&absolute(path))).collect()
+}
+
+// Run rebuild whenever an input changes or the inputs resolve to different
+// files, once a burst of changes has been quiet for the debounce delay.
+// Returns when the watcher stops.
+pub fn watch(
+    roots: &BTreeMap<PathBuf, bool>,
+    ignored: &[PathBuf],
+    debounce: Duration,
+    resolve: impl Fn() -> Result<Vec<PathBuf>>,
+    mut rebuild: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Failed to start watching for changes")?;
+    for (root, &recursive) in roots {
+        let mode = if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+        watcher.watch(root, mode).with_context(|| format!("Failed to watch: {}", root.display()))?;
+    }
+    let ignored: Vec<PathBuf> = ignored.iter().map(|path| absolute(path)).collect();
+    let mut resolved = without_outputs(resolve()?, &ignored);
+    status!("Watching {} directories for changes (Ctrl+C to stop)", roots.len());
+
+    while let Ok(event) = receiver.recv() {
+        let mut changed = BTreeSet::new();
+        collect_changes(event, &ignored, &mut changed);
+        loop {
+            match receiver.recv_timeout(debounce) {
+                Ok(event) => collect_changes(event, &ignored, &mut changed),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+        if changed.is_empty() {
+            continue;
+        }
+
+        let inputs: BTreeSet<PathBuf> = resolved.iter().map(|path| absolute(path)).collect();
+        let changed_inputs: Vec<&PathBuf> = changed.iter().filter(|path| inputs.contains(*path)).collect();
+        let current = match resolve() {
+            Ok(current) => without_outputs(current, &ignored),
+            Err(error) => {
+                eprintln!("Error: Failed to resolve inputs: {:#}", error);
+                continue;
+            }
+        };
+        if changed_inputs.is_empty() && current == resolved {
+            continue;
+        }
+        if current != resolved {
+            status!("\nInputs changed ({} files now match), rebuilding...", current.len());
+        } else if let [path] = changed_inputs.as_slice() {
+            status!("\n{} changed, rebuilding...", relative_path(path));
+        } else {
+            status!("\n{} inputs changed, rebuilding...", changed_inputs.len());
+        }
+        resolved = current;
+        // A failed rebuild leaves the previous output in place
+        if let Err(error) = rebuild() {
+            eprintln!("Error: Rebuild failed: {:#}", error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_watch_root() -> Result<()> {
+        assert_eq!(watch_root("src/*.rs", false), (PathBuf::from("src"), false));
+        assert_eq!(watch_root("src/*.rs", true), (PathBuf::from("src"), true));
+        assert_eq!(watch_root("src/**/*.rs", false), (PathBuf::from("src"), true));
+        assert_eq!(watch_root("*.txt", false), (PathBuf::from("."), false));
+
+        let temp_dir = TempDir::new()?;
+        let file = temp_dir.path().join("a.txt");
+        fs::write(&file, "a\n")?;
+        fs::write(temp_dir.path().join("logs.tar.gz"), "")?;
+        let members = format!("{}//*.log", temp_dir.path().join("logs.tar.gz").display());
+        assert_eq!(watch_root(&members, true), (temp_dir.path().to_path_buf(), false));
+        assert_eq!(watch_root(&file.to_string_lossy(), true), (temp_dir.path().to_path_buf(), false));
+        assert_eq!(watch_root(&temp_dir.path().to_string_lossy(), true), (temp_dir.path().to_path_buf(), true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_changes_skips_reads_and_outputs() {
+        let ignored = vec![PathBuf::from("/work/out.txt")];
+        let mut changed = BTreeSet::new();
+        let event = |kind, path: &str| Ok(notify::Event::new(kind).add_path(PathBuf::from(path)));
+        collect_changes(event(EventKind::Access(notify::event::AccessKind::Any), "/work/a.txt"), &ignored, &mut changed);
+        collect_changes(event(EventKind::Modify(notify::event::ModifyKind::Any), "/work/out.txt"), &ignored, &mut changed);
+        collect_changes(event(EventKind::Create(notify::event::CreateKind::File), "/work/b.txt"), &ignored, &mut changed);
+        assert_eq!(changed.into_iter().collect::<Vec<_>>(), vec![PathBuf::from("/work/b.txt")]);
+    }
+
+    #[test]
+    fn test_resolved_inputs_leave_out_outputs() {
+        let ignored = vec![absolute(Path::new("run.json"))];
+        let files = vec![PathBuf::from("data.json"), PathBuf::from("run.json")];
+        assert_eq!(without_outputs(files, &ignored), vec![PathBuf::from("data.json")]);
+    }
+}